@@ -1,5 +1,6 @@
 use std::error::Error;
 mod str_utils;
+use std::fmt;
 use std::io::{stdin, Read};
 use std::str::FromStr;
 use std::sync::mpsc::{Receiver, Sender};
@@ -20,6 +21,38 @@ pub enum Transformation {
     Reverse,
 }
 
+impl Transformation {
+    // Every transformation, e.g. to offer them all in another application
+    pub const ALL: [Transformation; 7] = [
+        Transformation::Lowercase,
+        Transformation::Uppercase,
+        Transformation::NoSpaces,
+        Transformation::Slugify,
+        Transformation::Csv,
+        Transformation::Double,
+        Transformation::Reverse,
+    ];
+
+    // Name of the transformation, accepted by from_str()
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transformation::Lowercase => "lowercase",
+            Transformation::Uppercase => "uppercase",
+            Transformation::NoSpaces => "no-spaces",
+            Transformation::Slugify => "slugify",
+            Transformation::Csv => "csv",
+            Transformation::Double => "double",
+            Transformation::Reverse => "reverse",
+        }
+    }
+}
+
+impl fmt::Display for Transformation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
 #[derive(PartialEq)]
 pub enum AppType {
     Threaded,
@@ -202,6 +235,16 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_names_round_trip() {
+        for transformation in Transformation::ALL {
+            assert_eq!(
+                Transformation::from_str(transformation.name()).unwrap(),
+                transformation
+            );
+        }
+    }
+
     #[test]
     fn test_empty_string() {
         assert!(transform("", Transformation::Reverse, AppType::Unattended).is_err());
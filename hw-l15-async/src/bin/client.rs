@@ -1,8 +1,8 @@
 use anyhow::{Context, Result};
-use env_logger;
 use log::info;
 use networking::client::start_client;
-use networking::common::parse_addr;
+use networking::common::saving::SaveConfig;
+use networking::common::{parse_codec, parse_endpoint, parse_name};
 use std::env;
 
 #[tokio::main]
@@ -10,15 +10,30 @@ async fn main() -> Result<()> {
     // Initialize the logger
     env_logger::init();
 
-    // Parse port and ipv4 addr from the arguments
+    // Parse port and ipv4 addr, or `--unix <path>`, from the arguments
     let args: Vec<String> = env::args().collect();
-    let (ip, port) = parse_addr(&args[1..]).context("Failed to parse address")?;
-    info!("Parsed address is: {}:{}", ip, port);
+    let endpoint = parse_endpoint(&args[1..]).context("Failed to parse address")?;
+    let name = parse_name(&args[1..]);
+    let codec = parse_codec(&args[1..]).context("Failed to parse codec")?;
+    info!("Parsed address is: {endpoint}");
+    // Bots authenticate with the API token issued by the server's admin
+    let token = env::var("BOT_TOKEN").ok();
+    // People with an account log in with its password
+    let password = env::var("CHAT_PASSWORD").ok();
+    // Downloads go where DOWNLOAD_DIR says, taken names are handled as DOWNLOAD_OVERWRITE says
+    let saving = SaveConfig::from_env().context("Failed to parse download settings")?;
 
     // Start the client
-    start_client(ip, port)
-        .await
-        .context("Client execution finished error")?;
+    start_client(
+        &endpoint,
+        &name,
+        token.as_deref(),
+        password.as_deref(),
+        codec,
+        saving,
+    )
+    .await
+    .context("Client execution finished error")?;
     info!("Client execution finished without error");
     Ok(())
 }
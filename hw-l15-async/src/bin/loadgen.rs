@@ -0,0 +1,54 @@
+use anyhow::{Context, Ok, Result};
+use log::info;
+use networking::common::parse_addr;
+use networking::loadgen::{run, LoadConfig};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Initialize the logger
+    env_logger::init();
+
+    // Parse port and ipv4 addr from the arguments
+    let args: Vec<String> = env::args().collect();
+    let (ip, port) = parse_addr(&args[1..]).context("Failed to parse address")?;
+    info!("Parsed address is: {}:{}", ip, port);
+    // Parse the load, every setting has a default
+    let mut config = LoadConfig {
+        ip,
+        port,
+        ..LoadConfig::default()
+    };
+    if let Some(clients) = args.get(3) {
+        config.clients = clients
+            .parse()
+            .context("Failed to parse number of clients")?;
+    }
+    if let Some(rate) = args.get(4) {
+        config.rate = rate.parse().context("Failed to parse request rate")?;
+    }
+    if let Some(duration) = args.get(5) {
+        let secs = duration.parse().context("Failed to parse duration")?;
+        config.duration = Duration::from_secs(secs);
+    }
+    if let Some(mix) = args.get(6) {
+        config.mix = mix.parse().context("Failed to parse request mix")?;
+    }
+    if let Some(file) = args.get(7) {
+        config.file = PathBuf::from(file);
+    }
+    if let Some(image) = args.get(8) {
+        config.image = PathBuf::from(image);
+    }
+
+    // Run the load and print the results for people and for scripts
+    let report = run(config).await.context("Load test failed")?;
+    println!("{report}");
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).context("Failed to encode report")?
+    );
+    Ok(())
+}
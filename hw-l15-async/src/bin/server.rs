@@ -1,9 +1,16 @@
-use anyhow::{Context, Ok, Result};
-use env_logger;
+use anyhow::{Context, Result};
 use log::info;
-use networking::common::parse_addr;
-use networking::server::start_server;
+use networking::common::{parse_addr, take_option, take_unix_path};
+use networking::server::accounts::hash_password;
+use networking::server::hub::Hub;
+use networking::server::mailbox::MailboxConfig;
+use networking::server::{Server, ServerConfig};
 use std::env;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -11,14 +18,152 @@ async fn main() -> Result<()> {
     env_logger::init();
 
     // Parse port and ipv4 addr from the arguments
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // The optional Unix socket may be anywhere, e.g. `--unix /run/chat.sock`
+    let unix = take_unix_path(&mut args).context("Failed to parse Unix socket")?;
+    // So may the mailbox limits of the offline users and the directory keeping the mailboxes
+    let mut mailbox = MailboxConfig::default();
+    if let Some(capacity) = take_option(&mut args, "--mailbox-capacity")? {
+        mailbox.capacity = capacity
+            .parse()
+            .context("Failed to parse mailbox capacity")?;
+    }
+    if let Some(expiry) = take_option(&mut args, "--mailbox-expiry")? {
+        let secs = expiry.parse().context("Failed to parse mailbox expiry")?;
+        mailbox.expiry = Duration::from_secs(secs);
+    }
+    info!("Mailbox limits are: {:?}", mailbox);
+    let data_dir = take_option(&mut args, "--data-dir")?.map(PathBuf::from);
     let (ip, port) = parse_addr(&args[1..]).context("Failed to parse address")?;
     info!("Parsed address is: {}:{}", ip, port);
+    // Parse the WebSocket port, the server defaults to the one following the TCP port
+    let ws_port = args
+        .get(3)
+        .map(|ws_port| ws_port.parse::<u16>())
+        .transpose()
+        .context("Failed to parse WebSocket port")?;
 
-    // Start the server
-    start_server(ip, port)
+    // Start the server and serve until it crashes
+    let config = ServerConfig {
+        ip,
+        port,
+        ws_port,
+        unix,
+        mailbox,
+        data_dir,
+        ..ServerConfig::default()
+    };
+    let server = Server::builder()
+        .config(config)
+        .start()
+        .await
+        .context("Failed to start server")?;
+    info!(
+        "Serving on {} and {}",
+        server.local_addr(),
+        server.ws_addr()
+    );
+    admin_console(server.hub().clone());
+    server
+        .join()
         .await
         .context("Server execution finished error")?;
     info!("Server execution finished without error");
     Ok(())
 }
+
+/// Runs the admin commands typed on the standard input in a separate thread.
+fn admin_console(hub: Arc<Hub>) {
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            let output = admin(&hub, &line);
+            if !output.is_empty() {
+                println!("{output}");
+            }
+        }
+    });
+}
+
+/// Runs an admin command, e.g. `bot issue ci builds,lobby` or `user add root <password> admin`,
+/// returns what to show the admin.
+fn admin(hub: &Hub, line: &str) -> String {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        [] => String::new(),
+        ["bot", "issue", name, rooms] => {
+            let rooms: Vec<String> = rooms
+                .split(',')
+                .filter(|room| !room.is_empty())
+                .map(String::from)
+                .collect();
+            // The token is shown only here, the server keeps its hash
+            match hub.bots().issue(name, &rooms) {
+                Ok((id, token)) => format!("Token {id} of bot {name}: {token}"),
+                Err(e) => e.to_string(),
+            }
+        }
+        ["bot", "revoke", id] => match id.parse() {
+            Ok(id) => match hub.bots().revoke(id) {
+                Ok(grant) => format!("Revoked token {id} of bot {}", grant.name),
+                Err(e) => e.to_string(),
+            },
+            Err(_) => format!("Invalid token ID {id}"),
+        },
+        ["bot", "list"] => {
+            let grants = hub.bots().list();
+            if grants.is_empty() {
+                return String::from("No bot tokens");
+            }
+            grants
+                .iter()
+                .map(|grant| {
+                    format!(
+                        "Token {} of bot {} in {}",
+                        grant.id,
+                        grant.name,
+                        grant.rooms.join(", ")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        ["user", "add", name, password] | ["user", "add", name, password, "admin"] => {
+            let admin = words.len() == 5;
+            // Argon2 is slow, the sessions checking their account mustn't wait for it
+            let added =
+                hash_password(password).and_then(|hash| hub.accounts().insert(name, hash, admin));
+            match added {
+                Ok(()) => format!("Added the account of {name}"),
+                Err(e) => e.to_string(),
+            }
+        }
+        ["user", "remove", name] => match hub.remove_account(name) {
+            Ok(()) => format!("Removed the account of {name}"),
+            Err(e) => e.to_string(),
+        },
+        ["user", "disable", name] => match hub.disable_account(name) {
+            Ok(()) => format!("Disabled the account of {name}"),
+            Err(e) => e.to_string(),
+        },
+        ["user", "list"] => {
+            let accounts = hub.accounts().list();
+            if accounts.is_empty() {
+                return String::from("No accounts");
+            }
+            accounts
+                .iter()
+                .map(|account| {
+                    let admin = if account.admin { ", admin" } else { "" };
+                    let disabled = if account.disabled { ", disabled" } else { "" };
+                    format!("Account of {}{admin}{disabled}", account.name)
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        _ => String::from(
+            "Use bot issue <name> <room>[,<room>...], bot revoke <id>, bot list, \
+             user add <name> <password> [admin], user remove <name>, user disable <name> \
+             or user list",
+        ),
+    }
+}
@@ -0,0 +1,26 @@
+use anyhow::{Context, Result};
+use networking::client::tui::start_tui;
+use networking::common::saving::SaveConfig;
+use networking::common::{parse_addr, parse_codec, parse_name};
+use std::env;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // The logger is not initialized, its output would corrupt the screen
+
+    // Parse port and ipv4 addr from the arguments
+    let args: Vec<String> = env::args().collect();
+    let (ip, port) = parse_addr(&args[1..]).context("Failed to parse address")?;
+    let name = parse_name(&args[1..]);
+    let codec = parse_codec(&args[1..]).context("Failed to parse codec")?;
+    // People with an account log in with its password
+    let password = env::var("CHAT_PASSWORD").ok();
+    // Downloads go where DOWNLOAD_DIR says, taken names are handled as DOWNLOAD_OVERWRITE says
+    let saving = SaveConfig::from_env().context("Failed to parse download settings")?;
+
+    // Start the terminal user interface
+    start_tui(ip, port, &name, password.as_deref(), codec, saving)
+        .await
+        .context("Client execution finished error")?;
+    Ok(())
+}
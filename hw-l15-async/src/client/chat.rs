@@ -0,0 +1,235 @@
+use crate::client::{create_client, hello, introduce};
+use crate::common::codec::{Codec, WireFormat};
+use crate::common::envelope::{AckState, Envelope};
+use crate::common::handshake::{Hello, Welcome};
+use crate::common::{Endpoint, LibError, MessageType};
+#[cfg(not(unix))]
+use anyhow::bail;
+use anyhow::{Context as _, Result};
+use futures_util::Stream;
+use log::info;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+
+/// Sending half of the connection, whatever the transport.
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Connection to the server driven from code, e.g. by a script or a bot.
+///
+/// Received messages are acknowledged as delivered automatically, read receipts are sent with
+/// `mark_read`.
+pub struct ChatClient {
+    name: String,
+    wire: WireFormat,
+    welcome: Welcome,
+    writer: Arc<Mutex<Writer>>,
+}
+
+/// Messages received from the server, ending after `Quit` or when the connection closes.
+pub struct Events {
+    incoming: mpsc::Receiver<Result<Envelope, LibError>>,
+}
+
+impl ChatClient {
+    /// Connects to the server and introduces the client, returns the client and its events.
+    pub async fn connect(
+        ip: Ipv4Addr,
+        port: u16,
+        name: &str,
+        codec: Codec,
+    ) -> Result<(ChatClient, Events)> {
+        ChatClient::connect_as(&Endpoint::Tcp(ip, port), &hello(name, codec)).await
+    }
+
+    /// Connects to the server over a Unix socket, returns the client and its events.
+    #[cfg(unix)]
+    pub async fn connect_unix(
+        path: &Path,
+        name: &str,
+        codec: Codec,
+    ) -> Result<(ChatClient, Events)> {
+        let endpoint = Endpoint::Unix(path.to_path_buf());
+        ChatClient::connect_as(&endpoint, &hello(name, codec)).await
+    }
+
+    /// Connects to the endpoint, returns the client and its events.
+    pub async fn connect_to(
+        endpoint: &Endpoint,
+        name: &str,
+        codec: Codec,
+    ) -> Result<(ChatClient, Events)> {
+        ChatClient::connect_as(endpoint, &hello(name, codec)).await
+    }
+
+    /// Connects as a bot authenticated with the API token, returns the client and its events.
+    pub async fn connect_bot(
+        endpoint: &Endpoint,
+        name: &str,
+        token: &str,
+        codec: Codec,
+    ) -> Result<(ChatClient, Events)> {
+        ChatClient::connect_as(endpoint, &hello(name, codec).with_token(token)).await
+    }
+
+    /// Logs into the account of the name with its password, returns the client and its events.
+    pub async fn login(
+        endpoint: &Endpoint,
+        name: &str,
+        password: &str,
+        codec: Codec,
+    ) -> Result<(ChatClient, Events)> {
+        ChatClient::connect_as(endpoint, &hello(name, codec).with_password(password)).await
+    }
+
+    /// Resumes the session of the token within its TTL, returns the client and its events.
+    ///
+    /// The events start with the messages missed since the previous connection broke.
+    pub async fn resume(
+        endpoint: &Endpoint,
+        name: &str,
+        session_token: &str,
+        codec: Codec,
+    ) -> Result<(ChatClient, Events)> {
+        ChatClient::connect_as(endpoint, &hello(name, codec).resuming(session_token)).await
+    }
+
+    /// Connects to the endpoint and introduces the client with the Hello.
+    async fn connect_as(endpoint: &Endpoint, hello: &Hello) -> Result<(ChatClient, Events)> {
+        match endpoint {
+            Endpoint::Tcp(ip, port) => {
+                let stream = create_client(*ip, *port).await?;
+                ChatClient::over(stream, hello).await
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", path.display()))?;
+                info!("Connected to {}", path.display());
+                ChatClient::over(stream, hello).await
+            }
+            #[cfg(not(unix))]
+            Endpoint::Unix(_) => bail!("Unix sockets aren't supported on this platform"),
+        }
+    }
+
+    /// Introduces the client over an established connection, returns the client and its events.
+    pub async fn over<S>(mut stream: S, hello: &Hello) -> Result<(ChatClient, Events)>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let name = hello.client_name.as_str();
+        let welcome = introduce(&mut stream, hello).await?;
+        let wire = WireFormat::from(&welcome);
+        let (reader, writer) = tokio::io::split(stream);
+        let writer: Arc<Mutex<Writer>> = Arc::new(Mutex::new(Box::new(writer)));
+        // Receive in a separate task, so the events keep coming while the caller sends
+        let (tx, incoming) = mpsc::channel(32);
+        tokio::spawn(dispatch(reader, wire, name.to_string(), writer.clone(), tx));
+        let client = ChatClient {
+            name: name.to_string(),
+            wire,
+            welcome,
+            writer,
+        };
+        Ok((client, Events { incoming }))
+    }
+
+    /// Returns the name the client introduced itself with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the settings the server agreed to, with the token to resume the session with.
+    pub fn welcome(&self) -> &Welcome {
+        &self.welcome
+    }
+
+    /// Sends a text, either to the room or a command such as `.join rust`, returns its ID.
+    pub async fn send_text(&self, text: &str) -> Result<Uuid, LibError> {
+        self.send(MessageType::from_text(text)).await
+    }
+
+    /// Asks the server for a file, returns the ID of the request.
+    pub async fn request_file(&self, path: &Path) -> Result<Uuid, LibError> {
+        self.send_text(&format!(".file {}", path.display())).await
+    }
+
+    /// Asks the server for an image, returns the ID of the request.
+    pub async fn request_image(&self, path: &Path) -> Result<Uuid, LibError> {
+        self.send_text(&format!(".image {}", path.display())).await
+    }
+
+    /// Asks the server to close the connection, the events end with its `Quit`.
+    pub async fn quit(&self) -> Result<(), LibError> {
+        self.send(MessageType::Quit).await.map(|_| ())
+    }
+
+    /// Wraps the message into an envelope and sends it, returns its ID.
+    pub async fn send(&self, message: MessageType) -> Result<Uuid, LibError> {
+        let envelope = Envelope::new(&self.name, message);
+        self.send_envelope(&envelope).await?;
+        Ok(envelope.id)
+    }
+
+    /// Sends an envelope as it is, e.g. to track its receipts beforehand.
+    pub async fn send_envelope(&self, envelope: &Envelope) -> Result<(), LibError> {
+        let mut writer = self.writer.lock().await;
+        envelope.send(&mut *writer, self.wire).await
+    }
+
+    /// Lets the sender of the message know it was shown to the user.
+    pub async fn mark_read(&self, envelope: &Envelope) -> Result<(), LibError> {
+        self.send_envelope(&envelope.ack(&self.name, AckState::Read))
+            .await
+    }
+}
+
+impl Stream for Events {
+    type Item = Result<Envelope, LibError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx)
+    }
+}
+
+/// Receives the messages, acknowledges their delivery and passes them on to the events.
+async fn dispatch<R: AsyncRead + Unpin>(
+    reader: R,
+    wire: WireFormat,
+    name: String,
+    writer: Arc<Mutex<Writer>>,
+    tx: mpsc::Sender<Result<Envelope, LibError>>,
+) {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut received = Envelope::receive(&mut reader, wire).await;
+        if let Ok(envelope) = &received {
+            if envelope.needs_ack() {
+                let delivered = envelope.ack(&name, AckState::Delivered);
+                if let Err(e) = delivered.send(&mut *writer.lock().await, wire).await {
+                    received = Err(e);
+                }
+            }
+        }
+        let last = matches!(
+            received,
+            Err(_)
+                | Ok(Envelope {
+                    message: MessageType::Quit,
+                    ..
+                })
+        );
+        if tx.send(received).await.is_err() || last {
+            return;
+        }
+    }
+}
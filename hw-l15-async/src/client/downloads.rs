@@ -0,0 +1,86 @@
+use crate::client::e2e::Outcome;
+use crate::common::saving::{Progress, SaveConfig, Saved};
+use crate::common::{part_path, LibError, MessageType};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs::metadata;
+
+/// Files sent part by part, each part is followed by the request of the next one.
+#[derive(Default)]
+pub struct Downloads {
+    /// Command and path each file was requested with, by the name of the file.
+    requests: HashMap<String, (String, String)>,
+}
+
+impl Downloads {
+    /// Returns the request to send, continuing the partial download of the file where it broke.
+    pub async fn request(&mut self, request: &str, saving: &SaveConfig) -> String {
+        let Some((command, path)) = request.split_once(char::is_whitespace) else {
+            return request.to_string();
+        };
+        let path = path.trim();
+        // Requests of a given offset are sent as they are
+        if !matches!(command, ".file" | ".get") || path.starts_with('@') {
+            return request.to_string();
+        }
+        let Some(name) = Path::new(path).file_name() else {
+            return request.to_string();
+        };
+        let name = name.to_string_lossy().into_owned();
+        let saved = metadata(part_path(saving, &name))
+            .await
+            .map_or(0, |metadata| metadata.len());
+        self.requests
+            .insert(name, (command.to_string(), path.to_string()));
+        match saved {
+            0 => request.to_string(),
+            saved => format!("{command} @{saved} {path}"),
+        }
+    }
+
+    /// Saves a received part of a file, returns None for other messages.
+    ///
+    /// The outcome requests the next part, or reports where the file was saved once its SHA-256
+    /// is verified.
+    pub async fn receive(
+        &mut self,
+        message: &MessageType,
+        saving: &SaveConfig,
+    ) -> Option<Result<Outcome, LibError>> {
+        let MessageType::FilePart { name, total, .. } = message else {
+            return None;
+        };
+        let mut outcome = Outcome::default();
+        let next = match message.save_part(saving).await {
+            Ok(Progress::Next(next)) => next,
+            Ok(Progress::Done(saved)) => {
+                self.requests.remove(name);
+                let note = match saved {
+                    Saved::Written(_) => format!("Received file {name}, SHA-256 verified, {saved}"),
+                    Saved::Skipped(_) => format!("File {name} {saved}"),
+                };
+                outcome.notes.push(note);
+                return Some(Ok(outcome));
+            }
+            Err(e) => {
+                self.requests.remove(name);
+                return Some(Err(e));
+            }
+        };
+        match self.requests.get(name) {
+            Some((command, path)) => {
+                outcome
+                    .notes
+                    .push(format!("Received {next} of {total} bytes of {name}"));
+                outcome
+                    .send
+                    .push(MessageType::Text(format!("{command} @{next} {path}")));
+            }
+            // E.g. a part retransmitted after the client restarted
+            None => outcome.notes.push(format!(
+                "Saved {next} of {total} bytes of {name}, request it again to continue"
+            )),
+        }
+        Some(Ok(outcome))
+    }
+}
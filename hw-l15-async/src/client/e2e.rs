@@ -0,0 +1,279 @@
+use crate::common::crypto::{fingerprint, Identity};
+use crate::common::{LibError, MessageType};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Returns the directory keeping the identity keys of the clients.
+///
+/// `KEYS_DIR` if set, otherwise `networking/keys` under `$XDG_DATA_HOME` or `~/.local/share`.
+pub fn keys_dir() -> Result<PathBuf, LibError> {
+    keys_dir_from(|var| env::var_os(var).filter(|value| !value.is_empty()))
+}
+
+/// Resolves the keys directory from the variables `var` returns.
+fn keys_dir_from(var: impl Fn(&str) -> Option<OsString>) -> Result<PathBuf, LibError> {
+    if let Some(dir) = var("KEYS_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let data = match var("XDG_DATA_HOME") {
+        Some(data) => PathBuf::from(data),
+        None => PathBuf::from(var("HOME").ok_or(LibError::NoKeysDir)?).join(".local/share"),
+    };
+    Ok(data.join("networking").join("keys"))
+}
+
+/// Public key of another user as seen by this client.
+struct KnownKey {
+    key: [u8; 32],
+    verified: bool,
+}
+
+/// Messages to send and notes to show to the user.
+#[derive(Default)]
+pub struct Outcome {
+    pub send: Vec<MessageType>,
+    pub notes: Vec<String>,
+}
+
+/// Client side of the end-to-end encrypted direct messages.
+pub struct DirectMessages {
+    name: String,
+    identity: Identity,
+    keys: HashMap<String, KnownKey>,
+    /// Messages waiting for the public key of their recipient.
+    pending: HashMap<String, Vec<String>>,
+}
+
+impl DirectMessages {
+    /// Loads the identity of the client from `dir`, generating it on the first run.
+    pub async fn load(name: &str, dir: &Path) -> Result<Self, LibError> {
+        let path = dir.join(format!("{name}.key"));
+        Ok(DirectMessages {
+            name: name.to_string(),
+            identity: Identity::load_or_generate(&path).await?,
+            keys: HashMap::new(),
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Message publishing the client's public key through the server.
+    pub fn publish(&self) -> MessageType {
+        MessageType::PublicKey {
+            user: self.name.clone(),
+            key: self.identity.public_key(),
+        }
+    }
+
+    /// Handles the direct message commands, returns None for any other input.
+    pub fn command(&mut self, input: &str) -> Option<Result<Outcome, LibError>> {
+        let mut outcome = Outcome::default();
+        if let Some(args) = input.strip_prefix(".dm ") {
+            let Some((user, text)) = args.trim().split_once(' ') else {
+                outcome.notes.push(String::from("Usage: .dm <user> <text>"));
+                return Some(Ok(outcome));
+            };
+            match self.keys.get(user) {
+                Some(known) => match self.identity.encrypt(&self.name, user, &known.key, text) {
+                    Ok(message) => outcome.send.push(message),
+                    Err(e) => return Some(Err(e)),
+                },
+                None => {
+                    // Send the message once the server hands out the key
+                    self.pending
+                        .entry(user.to_string())
+                        .or_default()
+                        .push(text.to_string());
+                    outcome.send.push(request_key(user));
+                }
+            }
+        } else if input == ".fingerprint" {
+            outcome.notes.push(format!(
+                "Your fingerprint: {}",
+                fingerprint(&self.identity.public_key())
+            ));
+        } else if let Some(user) = input.strip_prefix(".fingerprint ") {
+            let user = user.trim();
+            match self.keys.get(user) {
+                Some(known) => outcome.notes.push(format!(
+                    "Fingerprint of {user}: {} ({})",
+                    fingerprint(&known.key),
+                    if known.verified {
+                        "verified"
+                    } else {
+                        "unverified"
+                    }
+                )),
+                None => outcome.send.push(request_key(user)),
+            }
+        } else if let Some(args) = input.strip_prefix(".verify ") {
+            let Some((user, expected)) = args.trim().split_once(' ') else {
+                outcome
+                    .notes
+                    .push(String::from("Usage: .verify <user> <fingerprint>"));
+                return Some(Ok(outcome));
+            };
+            let note = match self.keys.get_mut(user) {
+                Some(known) if normalize(&fingerprint(&known.key)) == normalize(expected) => {
+                    known.verified = true;
+                    format!("Public key of {user} verified")
+                }
+                Some(_) => {
+                    format!("Fingerprint mismatch, the key of {user} may belong to someone else!")
+                }
+                None => format!("No public key of {user} known yet, use .fingerprint {user}"),
+            };
+            outcome.notes.push(note);
+        } else {
+            return None;
+        }
+        Some(Ok(outcome))
+    }
+
+    /// Handles public keys and encrypted messages, returns None for any other message.
+    pub fn receive(&mut self, message: &MessageType) -> Option<Outcome> {
+        let mut outcome = Outcome::default();
+        match message {
+            MessageType::PublicKey { user, key } => {
+                if *user == self.name {
+                    return Some(outcome);
+                }
+                outcome.notes.extend(self.remember_key(user, key));
+                // Send the messages waiting for this key
+                for text in self.pending.remove(user).unwrap_or_default() {
+                    match self.identity.encrypt(&self.name, user, key, &text) {
+                        Ok(message) => outcome.send.push(message),
+                        Err(e) => outcome.notes.push(e.to_string()),
+                    }
+                }
+            }
+            MessageType::Encrypted {
+                from, sender_key, ..
+            } => {
+                outcome.notes.extend(self.remember_key(from, sender_key));
+                match self.identity.decrypt(message) {
+                    Ok(text) => {
+                        let verified = self.keys.get(from).is_some_and(|known| known.verified);
+                        outcome.notes.push(format!(
+                            "[dm from {from}{}] {text}",
+                            if verified { "" } else { ", unverified" }
+                        ));
+                    }
+                    Err(e) => outcome.notes.push(e.to_string()),
+                }
+            }
+            _ => return None,
+        }
+        Some(outcome)
+    }
+
+    /// Stores the key of the user, warns when it differs from the known one.
+    fn remember_key(&mut self, user: &str, key: &[u8; 32]) -> Option<String> {
+        let print = fingerprint(key);
+        let note = match self.keys.get(user) {
+            Some(known) if known.key == *key => return None,
+            Some(_) => format!(
+                "WARNING: the public key of {user} has changed, the new fingerprint is {print}"
+            ),
+            None => format!(
+                "Public key of {user} has fingerprint {print}, \
+                 confirm it with {user} and run .verify {user} <fingerprint>"
+            ),
+        };
+        // A changed key has to be verified again
+        let known = KnownKey {
+            key: *key,
+            verified: false,
+        };
+        self.keys.insert(user.to_string(), known);
+        Some(note)
+    }
+}
+
+/// Asks the server for the public key of the user.
+fn request_key(user: &str) -> MessageType {
+    MessageType::Text(format!(".key {user}"))
+}
+
+/// Removes the formatting of a fingerprint, so it can be compared.
+fn normalize(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(name: &str) -> DirectMessages {
+        DirectMessages {
+            name: name.to_string(),
+            identity: Identity::generate(),
+            keys: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_keys_dir_is_per_user() {
+        let vars = |set: &'static [(&str, &str)]| {
+            move |var: &str| {
+                set.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| OsString::from(value))
+            }
+        };
+        let home = keys_dir_from(vars(&[("HOME", "/home/alice")])).unwrap();
+        assert_eq!(
+            home,
+            PathBuf::from("/home/alice/.local/share/networking/keys")
+        );
+        let xdg = keys_dir_from(vars(&[("HOME", "/home/alice"), ("XDG_DATA_HOME", "/data")]));
+        assert_eq!(xdg.unwrap(), PathBuf::from("/data/networking/keys"));
+        let set = keys_dir_from(vars(&[("HOME", "/home/alice"), ("KEYS_DIR", "/keys")]));
+        assert_eq!(set.unwrap(), PathBuf::from("/keys"));
+        assert!(matches!(keys_dir_from(vars(&[])), Err(LibError::NoKeysDir)));
+    }
+
+    #[tokio::test]
+    async fn test_pending_message_is_sent_with_key() {
+        // The identities are kept in a directory of their own, never in the working one
+        let dir = env::temp_dir().join(format!("keys-{}", uuid::Uuid::new_v4()));
+        let mut alice = DirectMessages::load("alice", &dir).await.unwrap();
+        let mut bob = DirectMessages::load("bob", &dir).await.unwrap();
+        assert!(dir.join("alice.key").is_file() && dir.join("bob.key").is_file());
+        // The key is unknown, so it's requested first
+        let outcome = alice.command(".dm bob hello there").unwrap().unwrap();
+        assert!(matches!(&outcome.send[..], [MessageType::Text(text)] if text == ".key bob"));
+
+        let outcome = alice.receive(&bob.publish()).unwrap();
+        let [message] = &outcome.send[..] else {
+            panic!("Expected one message, got {:?}", outcome.send);
+        };
+        let notes = bob.receive(message).unwrap().notes;
+        assert_eq!(
+            notes.last().unwrap(),
+            "[dm from alice, unverified] hello there"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_and_key_change() {
+        let (mut alice, bob) = (client("alice"), client("bob"));
+        alice.receive(&bob.publish());
+        let print = fingerprint(&bob.identity.public_key()).to_uppercase();
+        let verify = format!(".verify bob {print}");
+        alice.command(&verify).unwrap().unwrap();
+        assert!(alice.keys["bob"].verified);
+
+        // Someone else claiming to be bob resets the verification
+        let notes = alice.receive(&client("bob").publish()).unwrap().notes;
+        assert!(notes[0].starts_with("WARNING"));
+        assert!(!alice.keys["bob"].verified);
+    }
+}
@@ -1,19 +1,112 @@
-use crate::common::MessageType;
+pub mod chat;
+pub mod downloads;
+pub mod e2e;
+pub mod receipts;
+pub mod tui;
+
+use crate::client::chat::{ChatClient, Events};
+use crate::client::downloads::Downloads;
+use crate::client::e2e::{keys_dir, DirectMessages, Outcome};
+use crate::client::receipts::Receipts;
+use crate::common::codec::Codec;
+use crate::common::envelope::{short_id, AckState, Envelope, SERVER_SENDER};
+use crate::common::handshake::{Feature, HandshakeReply, Hello, Welcome};
+use crate::common::saving::{is_taken, Overwrite, SaveConfig};
+use crate::common::{listing, presence};
+use crate::common::{Endpoint, LibError, MessageType};
 use anyhow::{Context, Result};
-use log::{info, trace};
+use futures_util::StreamExt;
+use log::{debug, info, trace, warn};
+use std::io::{self, BufRead};
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+/// Features the client can enable for a connection.
+const CLIENT_FEATURES: &[Feature] = &[Feature::Compression];
+/// Time to wait before resuming a broken connection.
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+
+/// State of the terminal kept across the connections of a resumed session.
+struct Console {
+    lines: mpsc::Receiver<io::Result<String>>,
+    stdin_open: bool,
+    receipts: Receipts,
+    dms: DirectMessages,
+    downloads: Downloads,
+    saving: SaveConfig,
+}
 
-/// Starts the client, connecting to the specified IP and port.
-pub async fn start_client(ip: Ipv4Addr, port: u16) -> Result<()> {
-    // Create the client stream
-    let stream = create_client(ip, port)
+/// How a connection of the client ended.
+enum Ended {
+    /// The server confirmed the client quits, or shut down.
+    Quit,
+    /// The connection broke, the session can be resumed.
+    Lost(anyhow::Error),
+}
+
+/// Starts the client, connecting to the specified endpoint, as a bot if a token is given or into
+/// the account of the name if a password is.
+///
+/// The received files and images are saved as the settings say.
+pub async fn start_client(
+    endpoint: &Endpoint,
+    name: &str,
+    token: Option<&str>,
+    password: Option<&str>,
+    codec: Codec,
+    saving: SaveConfig,
+) -> Result<()> {
+    // Connect and introduce the client to the server
+    let connected = match (token, password) {
+        (Some(token), _) => ChatClient::connect_bot(endpoint, name, token, codec).await,
+        (None, Some(password)) => ChatClient::login(endpoint, name, password, codec).await,
+        (None, None) => ChatClient::connect_to(endpoint, name, codec).await,
+    };
+    let (mut client, mut events) = connected.context("Failed to connect client")?;
+    // Load the identity used for the direct messages
+    let dms = DirectMessages::load(name, &keys_dir()?)
         .await
-        .context("Failed to create client")?;
-    // Start the client loop to handle communication with the server
-    client_loop(stream).await.context("Client loop crashed")?;
-    Ok(())
+        .context("Failed to load the identity key")?;
+    info!(
+        "Use one of the following requests:
+    .help to list the commands of the server
+    .dm <user> <text>
+    .fingerprint [user]
+    .verify <user> <fingerprint>
+Any other will be returned as a plain text"
+    );
+    let mut console = Console {
+        lines: read_stdin(),
+        stdin_open: true,
+        receipts: Receipts::default(),
+        dms,
+        downloads: Downloads::default(),
+        saving,
+    };
+    loop {
+        // Start the client loop to handle communication with the server
+        let ended = client_loop(&client, &mut events, &mut console)
+            .await
+            .context("Client loop crashed")?;
+        let lost = match ended {
+            Ended::Quit => return Ok(()),
+            Ended::Lost(e) => e,
+        };
+        // A broken connection is resumed, with the room messages missed meanwhile
+        let Some(session) = client.welcome().session_token.clone() else {
+            return Err(lost);
+        };
+        warn!("Connection lost ({lost:#}), resuming the session");
+        sleep(RESUME_DELAY).await;
+        (client, events) = ChatClient::resume(endpoint, name, &session, codec)
+            .await
+            .with_context(|| format!("Failed to resume the session after: {lost:#}"))?;
+    }
 }
 
 /// Creates a TcpStream to connect to the specified IP and port.
@@ -29,61 +122,339 @@ pub async fn create_client(ip: Ipv4Addr, port: u16) -> Result<TcpStream> {
     Ok(stream)
 }
 
-/// Main loop to handle communication with the server.
-async fn client_loop(mut stream: TcpStream) -> Result<()> {
+/// Introduces the client to the server and checks that their protocols are compatible.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    name: &str,
+    codec: Codec,
+) -> Result<Welcome> {
+    introduce(stream, &hello(name, codec)).await
+}
+
+/// Builds the Hello of this client.
+pub fn hello(name: &str, codec: Codec) -> Hello {
+    Hello::new(name, CLIENT_FEATURES, &[codec])
+}
+
+/// Sends the Hello, e.g. one carrying a bot token, and checks that the protocols are compatible.
+pub async fn introduce<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    hello: &Hello,
+) -> Result<Welcome> {
+    hello.send(stream).await.context("Hello sending failed")?;
+    let welcome = HandshakeReply::receive(stream)
+        .await
+        .context("Reply receiving failed")?
+        .into_welcome()?;
     info!(
-        "Use one of the following requests:
-    .image <image.png>
-    .file <file>
-    .quit
-Any other will be returned as a plain text"
+        "Server {} accepted protocol version {} with {} codec and features {:?}",
+        welcome.server_name, welcome.version, welcome.codec, welcome.features
     );
+    Ok(welcome)
+}
 
-    let stdin = io::stdin();
-    let mut reader = BufReader::new(stdin);
+/// Main loop to handle communication with the server, returns how the connection ended.
+async fn client_loop(
+    client: &ChatClient,
+    events: &mut Events,
+    console: &mut Console,
+) -> Result<Ended> {
+    let Console {
+        lines,
+        stdin_open,
+        receipts,
+        dms,
+        downloads,
+        saving,
+    } = console;
+    // Publish the public key, so others can send direct messages
+    send_all(client, receipts, vec![dms.publish()])
+        .await
+        .context("Public key sending failed")?;
+    info!("Insert the request");
 
     loop {
-        // Read user input from stdin
-        info!("Insert the request");
-        let mut input = String::new();
-        reader
-            .read_line(&mut input)
-            .await
-            .context("Failed to read a line from stdin")?;
-
-        // Send the request to the server
-        let request = input.trim().as_bytes();
-        trace!("Sending request: {:?}", request);
-        stream
-            .write_all(request)
-            .await
-            .context("Requset sending failed")?;
-        trace!("Waiting for the response");
+        tokio::select! {
+            line = lines.recv(), if *stdin_open => {
+                // Read user input from stdin
+                let input = match line.transpose().context("Failed to read a line from stdin")? {
+                    Some(input) => input,
+                    None => {
+                        // Ask the server to terminate, so the pending responses still arrive
+                        *stdin_open = false;
+                        String::from(".quit")
+                    }
+                };
+                let request = input.trim();
+                if request.is_empty() {
+                    continue;
+                }
 
-        // Receive the response from the server
-        let response = MessageType::receive(&mut stream)
-            .await
-            .context("Response receiving failed")?;
-        // Take action based on the response
-        match response {
-            MessageType::Text(text) => {
-                info!("Received text: {text}");
+                // Partial downloads continue where they broke
+                let request = downloads.request(request, saving).await;
+                // Direct messages are encrypted before leaving the client
+                trace!("Sending request: {:?}", request);
+                let outcome = match dms.command(&request) {
+                    Some(outcome) => outcome.context("Direct message failed")?,
+                    None => Outcome {
+                        send: vec![MessageType::from_text(&request)],
+                        notes: Vec::new(),
+                    },
+                };
+                // Send the request to the server
+                for note in outcome.notes {
+                    info!("{note}");
+                }
+                send_all(client, receipts, outcome.send)
+                    .await
+                    .context("Requset sending failed")?;
             }
-            MessageType::Image(_) => {
-                info!("Received image...");
-                response.to_image().await?;
+            // Messages pushed by the server show up while typing
+            response = events.next() => {
+                // Receive the response from the server
+                let response = match response.ok_or(LibError::ConnectionClosed).and_then(|r| r) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let lost = anyhow::Error::new(e).context("Response receiving failed");
+                        return Ok(Ended::Lost(lost));
+                    }
+                };
+                // Messages sent by this client are referred to by the ID the server gave them
+                if let MessageType::Accepted { request, id } = response.message {
+                    if let Some(preview) = receipts.reassign(request, id) {
+                        info!("Sent message {} '{preview}'", short_id(&id));
+                    }
+                    continue;
+                }
+                // Receipts of the messages sent by this client
+                if let MessageType::Ack { id, state, .. } = response.message {
+                    if let Some(note) = receipts.update(&response.sender, id, state) {
+                        info!("{note}");
+                    }
+                    continue;
+                }
+                match response.message {
+                    MessageType::Quit => {
+                        info!("Quitting");
+                        return Ok(Ended::Quit);
+                    }
+                    // Presence and typing are only shown, never saved nor acknowledged
+                    MessageType::Presence { ref user, status, last_seen } => {
+                        info!("{}", presence::describe(user, status, last_seen));
+                        continue;
+                    }
+                    MessageType::Typing { ref user } => {
+                        debug!("{user} is typing");
+                        continue;
+                    }
+                    _ => {}
+                }
+                // The message is printed right away, so it's read as well
+                let read = (response.needs_ack() && response.sender != SERVER_SENDER)
+                    .then(|| response.ack(client.name(), AckState::Read));
+                // The user decides about files that would replace existing ones
+                let saving = confirm_overwrite(saving, &response.message, lines).await?;
+                if let Some(outcome) = dms.receive(&response.message) {
+                    for note in outcome.notes {
+                        info!("{note}");
+                    }
+                    send_all(client, receipts, outcome.send)
+                        .await
+                        .context("Direct message sending failed")?;
+                } else if let Some(outcome) = downloads.receive(&response.message, &saving).await {
+                    // Parts of a large file are saved, and the next one requested
+                    match outcome {
+                        Ok(outcome) => {
+                            for note in outcome.notes {
+                                info!("{note}");
+                            }
+                            send_all(client, receipts, outcome.send)
+                                .await
+                                .context("Part request sending failed")?;
+                        }
+                        Err(e) => warn!("Download failed: {e}"),
+                    }
+                } else {
+                    handle_response(response, &saving).await;
+                }
+                if let Some(read) = read {
+                    client
+                        .send_envelope(&read)
+                        .await
+                        .context("Acknowledgment sending failed")?;
+                }
+            }
+        }
+    }
+}
+
+/// Asks whether a received file may replace the existing file of its name if the policy is to
+/// ask, returns the settings to save it with.
+async fn confirm_overwrite(
+    saving: &SaveConfig,
+    message: &MessageType,
+    lines: &mut mpsc::Receiver<io::Result<String>>,
+) -> Result<SaveConfig> {
+    if saving.overwrite != Overwrite::Ask {
+        return Ok(saving.clone());
+    }
+    let path = match message {
+        MessageType::File { .. } | MessageType::Image { .. } => message.save_path(saving),
+        // Only the last part is saved under the name of the file
+        MessageType::FilePart {
+            offset,
+            total,
+            content,
+            ..
+        } if offset + content.len() as u64 >= *total => message.save_path(saving),
+        _ => None,
+    };
+    let Some(path) = path else {
+        return Ok(saving.clone());
+    };
+    if !is_taken(&path).await {
+        return Ok(saving.clone());
+    }
+    info!(
+        "{} already exists, overwrite, rename or skip it? [o/r/s]",
+        path.display()
+    );
+    loop {
+        // Without input, the file is saved under another name
+        let Some(line) = lines.recv().await else {
+            return Ok(saving.with_overwrite(Overwrite::Rename));
+        };
+        let overwrite = match line.context("Failed to read a line from stdin")?.trim() {
+            "o" | "overwrite" => Overwrite::Overwrite,
+            "r" | "rename" => Overwrite::Rename,
+            "s" | "skip" => Overwrite::Skip,
+            _ => {
+                info!("Answer o, r or s");
+                continue;
             }
-            MessageType::File {
-                ref name,
-                content: _,
-            } => {
-                info!("Received file {name}");
-                response.to_file().await?;
+        };
+        return Ok(saving.with_overwrite(overwrite));
+    }
+}
+
+/// Reads the lines from stdin in a separate thread, the channel closes at the end of the input.
+///
+/// A blocking read of tokio's stdin would keep the runtime from shutting down after `.quit`.
+fn read_stdin() -> mpsc::Receiver<io::Result<String>> {
+    let (tx, rx) = mpsc::channel(1);
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let failed = line.is_err();
+            if tx.blocking_send(line).is_err() || failed {
+                return;
             }
-            MessageType::Quit => {
-                info!("Quitting");
-                return Ok(());
+        }
+    });
+    rx
+}
+
+/// Wraps the messages into envelopes and sends them, tracking their receipts.
+async fn send_all(
+    client: &ChatClient,
+    receipts: &mut Receipts,
+    messages: Vec<MessageType>,
+) -> Result<(), LibError> {
+    for message in messages {
+        let envelope = Envelope::new(client.name(), message);
+        // Its ID is shown once the server gave it one
+        receipts.track(&envelope);
+        client.send_envelope(&envelope).await?;
+    }
+    Ok(())
+}
+
+/// Takes action based on a message received from the server.
+///
+/// Files and images that can't be saved are reported, the client keeps running.
+async fn handle_response(envelope: Envelope, saving: &SaveConfig) {
+    let Envelope {
+        id,
+        sender,
+        bot,
+        message: response,
+        ..
+    } = envelope;
+    // Messages of the users are printed with their ID, so that their edits can be told apart
+    let from_user = sender != SERVER_SENDER;
+    match response {
+        MessageType::Text(text) if bot => {
+            info!("Received text {} from bot: {text}", short_id(&id));
+        }
+        MessageType::Text(text) if from_user => {
+            info!("Received text {}: {text}", short_id(&id));
+        }
+        MessageType::Text(text) => {
+            info!("Received text: {text}");
+        }
+        MessageType::Image {
+            ref name,
+            format,
+            width,
+            height,
+            ..
+        } => match response.to_image(saving).await {
+            Ok(saved) => {
+                info!("Received {format} image {name} of {width}x{height} pixels, {saved}")
             }
+            Err(e) => warn_unsaved(name, e),
+        },
+        MessageType::File { ref name, .. } => match response.to_file(saving).await {
+            Ok(saved) => info!("Received file {name}, {saved}"),
+            Err(e) => warn_unsaved(name, e),
+        },
+        // Printed lines can't change, so the new version is printed with the ID of the old one
+        MessageType::Edit { id, text } if from_user => {
+            info!(
+                "Message {} edited: [{sender}] {text} (edited)",
+                short_id(&id)
+            );
+        }
+        MessageType::Edit { id, text } => {
+            info!("Message {} edited: {text} (edited)", short_id(&id));
+        }
+        MessageType::Delete { id } if from_user => {
+            info!("Message {} of {sender} deleted", short_id(&id));
+        }
+        MessageType::Delete { id } => {
+            info!("Message {} deleted", short_id(&id));
+        }
+        MessageType::Error { kind, message } => {
+            warn!("Request refused ({kind:?}): {message}");
+        }
+        MessageType::Listing { dir, entries } => {
+            info!("{}", listing::table(&dir, &entries));
+        }
+        MessageType::Stat(entry) => {
+            info!("{}", listing::describe(&entry));
+        }
+        MessageType::Joined { room } => {
+            info!("Joined room {room}");
+        }
+        // Quit, receipts, presence, file parts and direct messages are handled by the caller,
+        // compressed messages are decompressed when received
+        MessageType::Quit
+        | MessageType::FilePart { .. }
+        | MessageType::Compressed(_)
+        | MessageType::PublicKey { .. }
+        | MessageType::Encrypted { .. }
+        | MessageType::Ack { .. }
+        | MessageType::Accepted { .. }
+        | MessageType::Presence { .. }
+        | MessageType::Typing { .. } => {}
+    }
+}
+
+/// Reports a received file or image that wasn't saved, e.g. one corrupted on the way.
+fn warn_unsaved(name: &str, e: LibError) {
+    match e {
+        LibError::ChecksumMismatch(_) | LibError::ImageHeaderMismatch(_) => {
+            warn!("Rejected download of {name}: {e}")
         }
+        e => warn!("Failed to save {name}: {e}"),
     }
 }
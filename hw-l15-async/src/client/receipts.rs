@@ -0,0 +1,127 @@
+use crate::common::envelope::{AckState, Envelope};
+use crate::common::MessageType;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use uuid::Uuid;
+
+/// Longest text shown when reporting the state of a message.
+const PREVIEW_LEN: usize = 40;
+
+/// Delivery state of a message sent by this client.
+pub struct Receipt {
+    pub preview: String,
+    delivered: BTreeSet<String>,
+    read: BTreeSet<String>,
+}
+
+/// Delivery state of the messages sent by this client, by their ID.
+#[derive(Default)]
+pub struct Receipts {
+    sent: HashMap<Uuid, Receipt>,
+}
+
+impl Receipts {
+    /// Starts tracking a message other users may acknowledge, returns its preview.
+    pub fn track(&mut self, envelope: &Envelope) -> Option<&str> {
+        let preview = match &envelope.message {
+            // Commands are only answered by the server
+            MessageType::Text(text) if !text.starts_with('.') => {
+                match text.char_indices().nth(PREVIEW_LEN) {
+                    Some((end, _)) => format!("{}...", &text[..end]),
+                    None => text.clone(),
+                }
+            }
+            MessageType::Encrypted { to, .. } => format!("[dm to {to}]"),
+            _ => return None,
+        };
+        let receipt = Receipt {
+            preview,
+            delivered: BTreeSet::new(),
+            read: BTreeSet::new(),
+        };
+        let receipt = self.sent.entry(envelope.id).or_insert(receipt);
+        Some(&receipt.preview)
+    }
+
+    /// Moves the state of a message to the ID the server gave it, returns its preview.
+    pub fn reassign(&mut self, request: Uuid, id: Uuid) -> Option<&str> {
+        let receipt = self.sent.remove(&request)?;
+        Some(&self.sent.entry(id).or_insert(receipt).preview)
+    }
+
+    /// Records a receipt from the user, returns a note describing it.
+    pub fn update(&mut self, user: &str, id: Uuid, state: AckState) -> Option<String> {
+        let receipt = self.sent.get_mut(&id)?;
+        let (added, verb) = match state {
+            AckState::Delivered => (receipt.delivered.insert(user.to_string()), "delivered to"),
+            AckState::Read => (receipt.read.insert(user.to_string()), "read by"),
+        };
+        // Retransmitted messages may be acknowledged twice
+        added.then(|| format!("'{}' {verb} {user}", receipt.preview))
+    }
+
+    /// Returns the delivery state of a sent message.
+    pub fn get(&self, id: &Uuid) -> Option<&Receipt> {
+        self.sent.get(id)
+    }
+}
+
+impl fmt::Display for Receipt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Read implies delivered, so every user is listed once
+        let delivered: Vec<&str> = self
+            .delivered
+            .difference(&self.read)
+            .map(String::as_str)
+            .collect();
+        let read: Vec<&str> = self.read.iter().map(String::as_str).collect();
+        match (delivered.is_empty(), read.is_empty()) {
+            (true, true) => write!(f, "sent"),
+            (false, true) => write!(f, "delivered to {}", delivered.join(", ")),
+            (true, false) => write!(f, "read by {}", read.join(", ")),
+            (false, false) => write!(
+                f,
+                "read by {}, delivered to {}",
+                read.join(", "),
+                delivered.join(", ")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_receipt_states() {
+        let mut receipts = Receipts::default();
+        let envelope = Envelope::new("alice", MessageType::from_text("hello"));
+        assert_eq!(receipts.track(&envelope), Some("hello"));
+        // The receipts refer to the ID the server gave the message
+        let id = Uuid::new_v4();
+        assert_eq!(receipts.reassign(envelope.id, id), Some("hello"));
+        assert!(receipts.get(&envelope.id).is_none());
+        assert_eq!(receipts.get(&id).unwrap().to_string(), "sent");
+
+        let note = receipts.update("bob", id, AckState::Delivered);
+        assert_eq!(note.unwrap(), "'hello' delivered to bob");
+        receipts.update("carol", id, AckState::Delivered);
+        receipts.update("bob", id, AckState::Read);
+        assert!(receipts.update("bob", id, AckState::Read).is_none());
+        assert_eq!(
+            receipts.get(&id).unwrap().to_string(),
+            "read by bob, delivered to carol"
+        );
+    }
+
+    #[test]
+    fn test_commands_are_not_tracked() {
+        let mut receipts = Receipts::default();
+        let envelope = Envelope::new("alice", MessageType::from_text(".join rust"));
+        assert!(receipts.track(&envelope).is_none());
+        assert!(receipts
+            .update("bob", envelope.id, AckState::Read)
+            .is_none());
+    }
+}
@@ -0,0 +1,577 @@
+use crate::client::downloads::Downloads;
+use crate::client::e2e::{keys_dir, DirectMessages, Outcome};
+use crate::client::receipts::Receipts;
+use crate::client::{create_client, hello, introduce};
+use crate::common::codec::{Codec, WireFormat};
+use crate::common::envelope::{short_id, AckState, Envelope, SERVER_SENDER};
+use crate::common::handshake::Welcome;
+use crate::common::presence::Status;
+use crate::common::saving::SaveConfig;
+use crate::common::{listing, receive_loop, MessageType};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local, Utc};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time;
+use uuid::Uuid;
+
+/// Help shown in the status bar.
+const SHORTCUTS: &str = "Enter send | Ctrl+F file | Ctrl+P image | PgUp/PgDn scroll | Esc quit";
+/// Shortest time between two typing signals sent by this client.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+/// How long a user is shown as typing after their last signal.
+const TYPING_SHOWN: Duration = Duration::from_secs(5);
+/// Follows the name of a bot in its messages.
+const BOT_MARK: &str = "(bot) ";
+
+/// Connection state shown in the status bar.
+enum ConnectionState {
+    Connecting,
+    Connected(String),
+    Disconnected(String),
+}
+
+/// State of the terminal user interface.
+struct App {
+    name: String,
+    messages: Vec<String>,
+    /// Lines showing the messages sent by this client, with the ID of the message.
+    sent: HashMap<usize, Uuid>,
+    /// Line and label, e.g. "[alice] ", of every message that may be edited, by its ID.
+    lines: HashMap<Uuid, (usize, String)>,
+    receipts: Receipts,
+    /// Read receipts to send once the line of the received message is shown.
+    unread: Vec<(usize, Envelope)>,
+    input: String,
+    room: String,
+    rooms: BTreeSet<String>,
+    /// Presence of the users seen in the room, with the time they were last seen if offline.
+    users: BTreeMap<String, (Status, Option<DateTime<Utc>>)>,
+    /// Users typing in the room, with the time of their last signal.
+    typing: HashMap<String, Instant>,
+    /// Time this client last signalled typing.
+    typed: Option<Instant>,
+    state: ConnectionState,
+    wire: WireFormat,
+    dms: DirectMessages,
+    downloads: Downloads,
+    /// Where the received files are saved, asking is left to the command-line client.
+    saving: SaveConfig,
+    scroll: usize,
+    /// Number of message lines that fit the pane, known after drawing.
+    height: usize,
+    quit: bool,
+}
+
+/// Starts the full-screen client, connecting to the specified IP and port.
+pub async fn start_tui(
+    ip: Ipv4Addr,
+    port: u16,
+    name: &str,
+    password: Option<&str>,
+    codec: Codec,
+    saving: SaveConfig,
+) -> Result<()> {
+    // Switch the terminal to the alternate screen, restoring it even on error
+    let mut terminal = ratatui::init();
+    let result = tui_loop(&mut terminal, ip, port, name, password, codec, saving).await;
+    ratatui::restore();
+    result
+}
+
+/// Main loop drawing the interface and reacting to the keyboard and the server.
+async fn tui_loop(
+    terminal: &mut DefaultTerminal,
+    ip: Ipv4Addr,
+    port: u16,
+    name: &str,
+    password: Option<&str>,
+    codec: Codec,
+    saving: SaveConfig,
+) -> Result<()> {
+    let dms = DirectMessages::load(name, &keys_dir()?)
+        .await
+        .context("Failed to load the identity key")?;
+    let mut app = App::new(name, dms, saving);
+    let mut events = EventStream::new();
+    terminal.draw(|frame| app.draw(frame))?;
+
+    // Connect and let a separate task receive the messages, so typing is never blocked
+    let (tx, mut incoming) = mpsc::channel(32);
+    let mut writer = match connect(ip, port, name, password, codec).await {
+        Ok((stream, welcome)) => {
+            app.state = ConnectionState::Connected(format!("{ip}:{port}"));
+            app.wire = WireFormat::from(&welcome);
+            let (reader, writer) = stream.into_split();
+            tokio::spawn(receive_loop(reader, app.wire, tx));
+            Some(writer)
+        }
+        Err(e) => {
+            app.state = ConnectionState::Disconnected(format!("{e:#}"));
+            None
+        }
+    };
+    // Publish the public key, so others can send direct messages
+    let publish = app.dms.publish();
+    app.send_all(&mut writer, vec![publish]).await;
+    // Redraw periodically, so that typing indicators fade out
+    let mut tick = time::interval(Duration::from_secs(1));
+
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        if app.quit {
+            return Ok(());
+        }
+        app.mark_read(&mut writer).await;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if let Some(request) = app.on_key(key) {
+                        app.send_request(&mut writer, &request).await;
+                    } else if app.should_signal_typing() {
+                        let typing = MessageType::Typing { user: app.name.clone() };
+                        app.send_all(&mut writer, vec![typing]).await;
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e).context("Failed to read terminal events"),
+                None => return Ok(()),
+            },
+            message = incoming.recv(), if writer.is_some() => match message {
+                Some(Ok(message)) => app.on_message(&mut writer, message).await,
+                Some(Err(e)) => {
+                    app.state = ConnectionState::Disconnected(e.to_string());
+                    writer = None;
+                }
+                None => writer = None,
+            },
+            _ = tick.tick() => {}
+        }
+    }
+}
+
+/// Connects to the server and introduces the client, logging into its account if there's a
+/// password.
+async fn connect(
+    ip: Ipv4Addr,
+    port: u16,
+    name: &str,
+    password: Option<&str>,
+    codec: Codec,
+) -> Result<(TcpStream, Welcome)> {
+    let mut stream = create_client(ip, port).await?;
+    let hello = match password {
+        Some(password) => hello(name, codec).with_password(password),
+        None => hello(name, codec),
+    };
+    let welcome = introduce(&mut stream, &hello).await?;
+    Ok((stream, welcome))
+}
+
+impl App {
+    fn new(name: &str, dms: DirectMessages, saving: SaveConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            messages: Vec::new(),
+            sent: HashMap::new(),
+            lines: HashMap::new(),
+            receipts: Receipts::default(),
+            unread: Vec::new(),
+            input: String::new(),
+            room: String::from("lobby"),
+            rooms: BTreeSet::from([String::from("lobby")]),
+            users: BTreeMap::new(),
+            typing: HashMap::new(),
+            typed: None,
+            state: ConnectionState::Connecting,
+            wire: WireFormat::default(),
+            dms,
+            downloads: Downloads::default(),
+            saving,
+            scroll: 0,
+            height: 0,
+            quit: false,
+        }
+    }
+
+    /// Updates the input line and returns the request to send, if any.
+    fn on_key(&mut self, key: KeyEvent) -> Option<String> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Enter => {
+                let request = self.input.trim().to_string();
+                self.input.clear();
+                self.scroll = 0;
+                return (!request.is_empty()).then_some(request);
+            }
+            KeyCode::Esc => {
+                self.quit = true;
+                return Some(String::from(".quit"));
+            }
+            KeyCode::Char('c') if ctrl => {
+                self.quit = true;
+                return Some(String::from(".quit"));
+            }
+            // Shortcuts prefilling the file and image requests
+            KeyCode::Char('f') if ctrl => self.input = String::from(".file "),
+            KeyCode::Char('p') if ctrl => self.input = String::from(".image "),
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Up => self.scroll_by(1),
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll_by(10),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        None
+    }
+
+    /// Returns whether the others should be told that the user is typing a message.
+    fn should_signal_typing(&mut self) -> bool {
+        // Commands aren't shown to the room, and the signal is throttled
+        if self.input.is_empty() || self.input.starts_with('.') {
+            return false;
+        }
+        if self.typed.is_some_and(|at| at.elapsed() < TYPING_INTERVAL) {
+            return false;
+        }
+        self.typed = Some(Instant::now());
+        true
+    }
+
+    /// Sends a request to the server, encrypting the direct messages first.
+    async fn send_request(&mut self, writer: &mut Option<OwnedWriteHalf>, request: &str) {
+        // Partial downloads continue where they broke
+        let request = self.downloads.request(request, &self.saving).await;
+        let request = request.as_str();
+        let outcome = match self.dms.command(request) {
+            Some(Ok(outcome)) => outcome,
+            Some(Err(e)) => Outcome {
+                send: Vec::new(),
+                notes: vec![e.to_string()],
+            },
+            None => Outcome {
+                send: vec![MessageType::from_text(request)],
+                notes: Vec::new(),
+            },
+        };
+        self.messages.extend(outcome.notes);
+        self.send_all(writer, outcome.send).await;
+    }
+
+    /// Sends the messages to the server, showing those other users may acknowledge.
+    async fn send_all(&mut self, writer: &mut Option<OwnedWriteHalf>, messages: Vec<MessageType>) {
+        for message in messages {
+            let envelope = Envelope::new(&self.name, message);
+            if let Some(preview) = self.receipts.track(&envelope) {
+                self.messages.push(format!("> {preview}"));
+                let line = self.messages.len() - 1;
+                self.sent.insert(line, envelope.id);
+                self.lines.insert(envelope.id, (line, String::from("> ")));
+            }
+            self.send_envelope(writer, &envelope).await;
+        }
+    }
+
+    /// Sends an envelope to the server, reporting failures in the message pane.
+    async fn send_envelope(&mut self, writer: &mut Option<OwnedWriteHalf>, envelope: &Envelope) {
+        let Some(stream) = writer else {
+            self.messages.push(String::from("Not connected"));
+            return;
+        };
+        if let Err(e) = envelope.send(stream, self.wire).await {
+            self.state = ConnectionState::Disconnected(e.to_string());
+            *writer = None;
+        }
+    }
+
+    /// Sends the read receipts of the received messages shown in the message pane.
+    async fn mark_read(&mut self, writer: &mut Option<OwnedWriteHalf>) {
+        let visible = self.visible();
+        let (shown, unread) = std::mem::take(&mut self.unread)
+            .into_iter()
+            .partition(|(line, _)| visible.contains(line));
+        self.unread = unread;
+        for (_, ack) in shown {
+            self.send_envelope(writer, &ack).await;
+        }
+    }
+
+    /// Takes action based on a message received from the server.
+    async fn on_message(&mut self, writer: &mut Option<OwnedWriteHalf>, envelope: Envelope) {
+        // Sent messages are referred to by the ID the server gave them
+        if let MessageType::Accepted { request, id } = envelope.message {
+            self.receipts.reassign(request, id);
+            if let Some(line) = self.lines.remove(&request) {
+                self.sent.insert(line.0, id);
+                self.lines.insert(id, line);
+            }
+            return;
+        }
+        // Receipts of the messages sent by this client are shown next to them
+        if let MessageType::Ack { id, state, .. } = envelope.message {
+            self.receipts.update(&envelope.sender, id, state);
+            return;
+        }
+        if envelope.needs_ack() {
+            let delivered = envelope.ack(&self.name, AckState::Delivered);
+            self.send_envelope(writer, &delivered).await;
+        }
+        let lines = self.messages.len();
+        let read = envelope.ack(&self.name, AckState::Read);
+        let from_user = envelope.sender != SERVER_SENDER && envelope.needs_ack();
+        let in_room = matches!(
+            envelope.message,
+            MessageType::Text(_) | MessageType::Image { .. } | MessageType::File { .. }
+        );
+        if from_user && in_room {
+            // The message was sent, so the user is in the room and no longer typing
+            self.typing.remove(&envelope.sender);
+            self.users.entry(envelope.sender.clone()).or_default();
+        }
+        // Relayed texts keep their label when edited
+        let mut message = envelope.message;
+        let mut label = match &message {
+            MessageType::Text(text) if from_user => {
+                text.find("] ").map(|end| text[..end + 2].to_string())
+            }
+            _ => None,
+        };
+        // Bots are marked right after their name
+        if let (true, Some(label), MessageType::Text(text)) =
+            (envelope.bot, &mut label, &mut message)
+        {
+            *text = format!("{label}{BOT_MARK}{}", &text[label.len()..]);
+            label.push_str(BOT_MARK);
+        }
+        self.on_content(writer, message).await;
+        if self.messages.len() > lines && from_user {
+            let line = self.messages.len() - 1;
+            if let Some(label) = label {
+                self.lines.insert(envelope.id, (line, label));
+            }
+            // The message is read once its last line is shown
+            self.unread.push((line, read));
+        }
+    }
+
+    /// Shows the content of a received message.
+    async fn on_content(&mut self, writer: &mut Option<OwnedWriteHalf>, message: MessageType) {
+        if let Some(outcome) = self.dms.receive(&message) {
+            self.messages.extend(outcome.notes);
+            self.send_all(writer, outcome.send).await;
+            return;
+        }
+        match self.downloads.receive(&message, &self.saving).await {
+            Some(Ok(outcome)) => {
+                self.messages.extend(outcome.notes);
+                self.send_all(writer, outcome.send).await;
+                return;
+            }
+            Some(Err(e)) => {
+                self.messages.push(format!("Download failed: {e}"));
+                return;
+            }
+            None => {}
+        }
+        match message {
+            MessageType::Text(text) => {
+                // Every line of e.g. the help gets its own line in the pane
+                self.messages.extend(text.lines().map(String::from));
+            }
+            MessageType::Joined { room } => {
+                self.messages.push(format!("Joined room {room}"));
+                // Users seen in the previous room are no longer relevant
+                self.rooms.insert(room.clone());
+                self.room = room;
+                self.users.clear();
+            }
+            MessageType::Image {
+                ref name,
+                format,
+                width,
+                height,
+                ..
+            } => match message.to_image(&self.saving).await {
+                Ok(saved) => self.messages.push(format!(
+                    "Received {format} image {name} of {width}x{height} pixels, {saved}"
+                )),
+                Err(e) => self.messages.push(format!("Failed to save image: {e}")),
+            },
+            MessageType::File { ref name, .. } => match message.to_file(&self.saving).await {
+                Ok(saved) => self.messages.push(format!("Received file {name}, {saved}")),
+                Err(e) => self
+                    .messages
+                    .push(format!("Failed to save file {name}: {e}")),
+            },
+            MessageType::Quit => {
+                self.state = ConnectionState::Disconnected(String::from("Connection closed"));
+                self.quit = true;
+            }
+            // Changes replace the line of the message instead of adding one
+            MessageType::Edit { id, text } => {
+                if let Some((line, label)) = self.lines.get(&id) {
+                    self.messages[*line] = format!("{label}{text} (edited)");
+                }
+            }
+            MessageType::Delete { id } => {
+                if let Some((line, label)) = self.lines.remove(&id) {
+                    self.messages[line] = format!("{label}(deleted)");
+                }
+            }
+            // Presence only updates the sidebar
+            MessageType::Presence {
+                user,
+                status,
+                last_seen,
+            } => {
+                self.typing.remove(&user);
+                self.users.insert(user, (status, last_seen));
+            }
+            MessageType::Typing { user } => {
+                self.typing.insert(user, Instant::now());
+            }
+            MessageType::Error { message, .. } => {
+                self.messages.push(format!("Error: {message}"));
+            }
+            MessageType::Listing { dir, entries } => {
+                let table = listing::table(&dir, &entries);
+                self.messages.extend(table.lines().map(String::from));
+            }
+            MessageType::Stat(entry) => self.messages.push(listing::describe(&entry)),
+            // Already decompressed when received, direct messages, file parts and receipts are
+            // handled above
+            MessageType::Compressed(_)
+            | MessageType::FilePart { .. }
+            | MessageType::PublicKey { .. }
+            | MessageType::Encrypted { .. }
+            | MessageType::Accepted { .. }
+            | MessageType::Ack { .. } => {}
+        }
+    }
+
+    /// Returns the indices of the message lines shown in the pane.
+    fn visible(&self) -> Range<usize> {
+        // Show the newest messages that fit, shifted by the scroll offset
+        let end = self.messages.len().saturating_sub(self.scroll);
+        end.saturating_sub(self.height)..end
+    }
+
+    /// Scrolls the message pane towards older messages.
+    fn scroll_by(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.messages.len().saturating_sub(1));
+    }
+
+    /// Renders the message pane, the sidebar, the input line and the status bar.
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, input, status] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [messages, sidebar] =
+            Layout::horizontal([Constraint::Min(20), Constraint::Length(24)]).areas(main);
+        let [rooms, users] =
+            Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(sidebar);
+
+        self.height = messages.height.saturating_sub(2) as usize;
+        let visible = self.visible();
+        let lines: Vec<Line> = visible
+            .clone()
+            .zip(&self.messages[visible])
+            .map(|(index, message)| {
+                // Sent messages show how far they got
+                let sent = self.sent.get(&index);
+                match sent.and_then(|id| Some((short_id(id), self.receipts.get(id)?))) {
+                    Some((id, receipt)) => Line::from(vec![
+                        message.as_str().into(),
+                        format!("  ({id}: {receipt})").dark_gray(),
+                    ]),
+                    None => Line::raw(message.as_str()),
+                }
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" Messages ")),
+            messages,
+        );
+
+        let rooms_list: Vec<Line> = self
+            .rooms
+            .iter()
+            .map(|room| match room == &self.room {
+                true => Line::raw(format!("# {room}")).bold(),
+                false => Line::raw(format!("  {room}")),
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(rooms_list).block(Block::bordered().title(" Rooms ")),
+            rooms,
+        );
+        let users_list: Vec<Line> = self
+            .users
+            .iter()
+            .map(|(user, (status, last_seen))| match (status, last_seen) {
+                (Status::Online, _) => Line::raw(format!("+ {user}")).green(),
+                (Status::Away, _) => Line::raw(format!("~ {user} (away)")).yellow(),
+                (Status::Busy, _) => Line::raw(format!("! {user} (busy)")).red(),
+                (Status::Offline, Some(at)) => Line::raw(format!(
+                    "- {user} ({})",
+                    at.with_timezone(&Local).format("%H:%M")
+                ))
+                .dark_gray(),
+                (Status::Offline, None) => Line::raw(format!("- {user}")).dark_gray(),
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(users_list).block(Block::bordered().title(" Users ")),
+            users,
+        );
+
+        self.typing.retain(|_, at| at.elapsed() < TYPING_SHOWN);
+        let mut typing: Vec<&str> = self.typing.keys().map(String::as_str).collect();
+        typing.sort_unstable();
+        let title = match typing.is_empty() {
+            true => String::from(" Request "),
+            false => format!(" Request | {} typing... ", typing.join(", ")),
+        };
+        frame.render_widget(
+            Paragraph::new(self.input.as_str()).block(Block::bordered().title(title)),
+            input,
+        );
+        frame.set_cursor_position((input.x + 1 + self.input.chars().count() as u16, input.y + 1));
+
+        let (state, color) = match &self.state {
+            ConnectionState::Connecting => (String::from("Connecting..."), Color::Yellow),
+            ConnectionState::Connected(addr) => (format!("Connected to {addr}"), Color::Green),
+            ConnectionState::Disconnected(reason) => {
+                (format!("Disconnected: {reason}"), Color::Red)
+            }
+        };
+        frame.render_widget(
+            Paragraph::new(Line::from(vec![
+                format!(" {state} ").fg(color).bold(),
+                format!("| {SHORTCUTS}").into(),
+            ]))
+            .style(Style::new().bg(Color::DarkGray)),
+            status,
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::common::handshake::{Feature, Welcome};
+use crate::common::{read_frame, write_frame, LibError, MAX_FRAME_LEN};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Wire encoding of the messages, chosen per connection during the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    /// Compact binary encoding in length-prefixed frames.
+    #[default]
+    Bincode,
+    /// Newline-delimited JSON, easy to inspect and to speak from other languages.
+    Json,
+    /// MessagePack with named fields in length-prefixed frames.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// Codec introduced by a newer peer, never negotiated.
+    #[serde(other)]
+    Unknown,
+}
+
+/// Encoding settings agreed on for a connection during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct WireFormat {
+    pub codec: Codec,
+    /// Whether File and Image payloads may be zstd-compressed.
+    pub compression: bool,
+}
+
+impl From<&Welcome> for WireFormat {
+    fn from(welcome: &Welcome) -> Self {
+        WireFormat {
+            codec: welcome.codec,
+            compression: welcome.features.contains(&Feature::Compression),
+        }
+    }
+}
+
+/// Codecs usable on a plain TCP connection, in the order of the server's preference.
+pub const ALL_CODECS: &[Codec] = &[Codec::Bincode, Codec::Json, Codec::MessagePack];
+
+impl Codec {
+    /// Serializes the message without any framing.
+    pub fn encode<T: Serialize>(&self, message: &T) -> Result<Vec<u8>, LibError> {
+        match self {
+            Codec::Bincode => Ok(bincode::serialize(message)?),
+            Codec::Json => Ok(serde_json::to_vec(message)?),
+            Codec::MessagePack => Ok(rmp_serde::to_vec_named(message)?),
+            Codec::Unknown => Err(LibError::UnsupportedCodec),
+        }
+    }
+
+    /// Deserializes a message encoded without any framing.
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, LibError> {
+        match self {
+            Codec::Bincode => Ok(bincode::deserialize(bytes)?),
+            Codec::Json => Ok(serde_json::from_slice(bytes)?),
+            Codec::MessagePack => Ok(rmp_serde::from_slice(bytes)?),
+            Codec::Unknown => Err(LibError::UnsupportedCodec),
+        }
+    }
+
+    /// Writes one framed message to the stream.
+    pub async fn write<W: AsyncWrite + Unpin, T: Serialize>(
+        &self,
+        stream: &mut W,
+        message: &T,
+    ) -> Result<(), LibError> {
+        let encoded = self.encode(message)?;
+        match self {
+            Codec::Json => {
+                // serde_json escapes newlines, so every message stays on a single line
+                stream.write_all(&encoded).await?;
+                stream.write_all(b"\n").await?;
+                Ok(())
+            }
+            _ => write_frame(stream, &encoded).await,
+        }
+    }
+
+    /// Reads one framed message from the stream.
+    pub async fn read<R: AsyncBufRead + Unpin, T: DeserializeOwned>(
+        &self,
+        stream: &mut R,
+    ) -> Result<T, LibError> {
+        match self {
+            Codec::Json => {
+                let mut line = Vec::new();
+                // Bound the line, a peer never sending a newline must not exhaust the memory
+                let n = (&mut *stream)
+                    .take(MAX_FRAME_LEN as u64 + 1)
+                    .read_until(b'\n', &mut line)
+                    .await?;
+                if n == 0 {
+                    return Err(LibError::ConnectionClosed);
+                }
+                if n > MAX_FRAME_LEN {
+                    return Err(LibError::FrameTooLarge(n));
+                }
+                self.decode(&line)
+            }
+            _ => self.decode(&read_frame(stream, MAX_FRAME_LEN).await?),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = LibError;
+
+    fn from_str(input: &str) -> Result<Codec, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "bincode" => Ok(Codec::Bincode),
+            "json" => Ok(Codec::Json),
+            "msgpack" | "messagepack" => Ok(Codec::MessagePack),
+            _ => Err(LibError::UnsupportedCodec),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Codec::Bincode => "bincode",
+            Codec::Json => "json",
+            Codec::MessagePack => "msgpack",
+            Codec::Unknown => "unknown",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::MessageType;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_round_trip_all_codecs() {
+        for codec in ALL_CODECS {
+            let mut buffer = Vec::new();
+            let file = MessageType::file("a.txt", b"line\nline".to_vec());
+            codec.write(&mut buffer, &file).await.unwrap();
+            codec.write(&mut buffer, &MessageType::Quit).await.unwrap();
+
+            // Back-to-back messages are read one by one
+            let mut reader = BufReader::new(buffer.as_slice());
+            match codec.read::<_, MessageType>(&mut reader).await.unwrap() {
+                MessageType::File { name, content, .. } => {
+                    assert_eq!(name, "a.txt");
+                    assert_eq!(content, b"line\nline");
+                }
+                other => panic!("{codec}: unexpected message {other:?}"),
+            }
+            assert!(matches!(
+                codec.read(&mut reader).await.unwrap(),
+                MessageType::Quit
+            ));
+            assert!(matches!(
+                codec.read::<_, MessageType>(&mut reader).await,
+                Err(LibError::ConnectionClosed)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_json_is_readable() {
+        let encoded = Codec::Json.encode(&MessageType::from_text("hi")).unwrap();
+        assert_eq!(encoded, br#"{"Text":"hi"}"#);
+    }
+
+    #[test]
+    fn test_parse_codec() {
+        assert_eq!("MsgPack".parse::<Codec>().unwrap(), Codec::MessagePack);
+        assert!("xml".parse::<Codec>().is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::common::{LibError, MessageType, MAX_FRAME_LEN};
+use log::trace;
+use std::io::Read;
+
+/// Payloads smaller than this are sent as they are, compressing them is not worth it.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+/// zstd level balancing the speed and the ratio.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Compresses the content of a File, FilePart or Image message, returns None if it's not worth it.
+pub fn compress(message: &MessageType) -> Result<Option<MessageType>, LibError> {
+    let compressed = match message {
+        MessageType::File {
+            name,
+            content,
+            sha256,
+        } if content.len() >= COMPRESSION_THRESHOLD => MessageType::File {
+            name: name.clone(),
+            content: zstd::encode_all(content.as_slice(), COMPRESSION_LEVEL)?,
+            sha256: *sha256,
+        },
+        MessageType::Image {
+            name,
+            format,
+            width,
+            height,
+            content,
+            sha256,
+        } if content.len() >= COMPRESSION_THRESHOLD => MessageType::Image {
+            name: name.clone(),
+            format: *format,
+            width: *width,
+            height: *height,
+            content: zstd::encode_all(content.as_slice(), COMPRESSION_LEVEL)?,
+            sha256: *sha256,
+        },
+        MessageType::FilePart {
+            name,
+            offset,
+            total,
+            content,
+            sha256,
+        } if content.len() >= COMPRESSION_THRESHOLD => MessageType::FilePart {
+            name: name.clone(),
+            offset: *offset,
+            total: *total,
+            content: zstd::encode_all(content.as_slice(), COMPRESSION_LEVEL)?,
+            sha256: *sha256,
+        },
+        _ => return Ok(None),
+    };
+
+    let (original, packed) = (content_len(message), content_len(&compressed));
+    trace!(
+        "Compressed payload from {original} to {packed} bytes, ratio {:.2}",
+        original as f64 / packed.max(1) as f64
+    );
+    // Incompressible data, e.g. PNG images, would only grow
+    if packed >= original {
+        return Ok(None);
+    }
+    Ok(Some(MessageType::Compressed(Box::new(compressed))))
+}
+
+/// Restores the original message if it was compressed, other messages are returned untouched.
+pub fn decompress(message: MessageType) -> Result<MessageType, LibError> {
+    let MessageType::Compressed(inner) = message else {
+        return Ok(message);
+    };
+    let packed = content_len(&inner);
+    let message = match *inner {
+        MessageType::File {
+            name,
+            content,
+            sha256,
+        } => MessageType::File {
+            name,
+            content: decode(&content)?,
+            sha256,
+        },
+        MessageType::Image {
+            name,
+            format,
+            width,
+            height,
+            content,
+            sha256,
+        } => MessageType::Image {
+            name,
+            format,
+            width,
+            height,
+            content: decode(&content)?,
+            sha256,
+        },
+        MessageType::FilePart {
+            name,
+            offset,
+            total,
+            content,
+            sha256,
+        } => MessageType::FilePart {
+            name,
+            offset,
+            total,
+            content: decode(&content)?,
+            sha256,
+        },
+        _ => return Err(LibError::WrongMessageType),
+    };
+    trace!(
+        "Decompressed payload from {packed} to {} bytes",
+        content_len(&message)
+    );
+    Ok(message)
+}
+
+/// Decodes zstd data, refusing to inflate beyond the frame size limit.
+fn decode(content: &[u8]) -> Result<Vec<u8>, LibError> {
+    let mut decoded = Vec::new();
+    zstd::Decoder::new(content)?
+        .take(MAX_FRAME_LEN as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > MAX_FRAME_LEN {
+        return Err(LibError::FrameTooLarge(decoded.len()));
+    }
+    Ok(decoded)
+}
+
+/// Returns the size of the File, FilePart or Image content.
+fn content_len(message: &MessageType) -> usize {
+    match message {
+        MessageType::File { content, .. }
+        | MessageType::Image { content, .. }
+        | MessageType::FilePart { content, .. } => content.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let file = MessageType::file("log.csv", b"time,level,message\n".repeat(500));
+        let compressed = compress(&file).unwrap().unwrap();
+        let decompressed = decompress(compressed).unwrap();
+        // The checksum is of the original content
+        decompressed.verify().unwrap();
+        match decompressed {
+            MessageType::File { name, content, .. } => {
+                assert_eq!(name, "log.csv");
+                assert_eq!(content, b"time,level,message\n".repeat(500));
+            }
+            other => panic!("Unexpected message {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_small_payload_is_skipped() {
+        let file = MessageType::file("a.txt", b"tiny".to_vec());
+        assert!(compress(&file).unwrap().is_none());
+        assert!(compress(&MessageType::from_text(&"a".repeat(5000)))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::common::{LibError, MessageType};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use sha2::{Digest, Sha256};
+use std::fs::Metadata;
+use std::path::Path;
+use tokio::fs::{DirBuilder, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Separates the keys derived here from any other use of the shared secret.
+const KEY_DOMAIN: &[u8] = b"networking direct message v1";
+
+/// Long-term X25519 key pair identifying a client in direct messages.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    /// Generates a fresh key pair.
+    pub fn generate() -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Identity { secret, public }
+    }
+
+    /// Loads the key pair from a file, generating and storing a new one if it doesn't exist.
+    ///
+    /// Only the owner may read the file, a key file other users can read is refused.
+    pub async fn load_or_generate(path: &Path) -> Result<Self, LibError> {
+        match File::open(path).await {
+            Ok(mut file) => {
+                check_private(path, &file.metadata().await?)?;
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).await?;
+                let bytes: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| LibError::CryptoError(format!("Malformed key file {path:?}")))?;
+                let secret = StaticSecret::from(bytes);
+                let public = PublicKey::from(&secret);
+                Ok(Identity { secret, public })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                if let Some(dir) = path.parent() {
+                    let mut builder = DirBuilder::new();
+                    builder.recursive(true);
+                    #[cfg(unix)]
+                    builder.mode(0o700);
+                    builder.create(dir).await?;
+                }
+                // Created with the owner-only permissions, never over an existing file
+                let mut options = OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                options.mode(0o600);
+                let mut file = options.open(path).await?;
+                file.write_all(&identity.secret.to_bytes()).await?;
+                file.flush().await?;
+                Ok(identity)
+            }
+            Err(e) => Err(LibError::IoError(e)),
+        }
+    }
+
+    /// Returns the public key to publish through the server.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Encrypts a direct message, the server only ever sees the ciphertext.
+    pub fn encrypt(
+        &self,
+        from: &str,
+        to: &str,
+        recipient_key: &[u8; 32],
+        text: &str,
+    ) -> Result<MessageType, LibError> {
+        let cipher = self.cipher(recipient_key);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = associated_data(from, to);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: text.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| LibError::CryptoError(String::from("Encryption failed")))?;
+        Ok(MessageType::Encrypted {
+            from: from.to_string(),
+            to: to.to_string(),
+            sender_key: self.public_key(),
+            nonce: nonce.into(),
+            ciphertext,
+        })
+    }
+
+    /// Decrypts a direct message, failing if it was tampered with.
+    pub fn decrypt(&self, message: &MessageType) -> Result<String, LibError> {
+        let MessageType::Encrypted {
+            from,
+            to,
+            sender_key,
+            nonce,
+            ciphertext,
+        } = message
+        else {
+            return Err(LibError::WrongMessageType);
+        };
+        let aad = associated_data(from, to);
+        let plaintext = self
+            .cipher(sender_key)
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| LibError::CryptoError(format!("Can't decrypt message from {from}")))?;
+        String::from_utf8(plaintext)
+            .map_err(|_| LibError::CryptoError(format!("Message from {from} is not a text")))
+    }
+
+    /// Derives the cipher shared with the peer owning the given public key.
+    fn cipher(&self, peer_key: &[u8; 32]) -> ChaCha20Poly1305 {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_key));
+        let key = Sha256::new()
+            .chain_update(KEY_DOMAIN)
+            .chain_update(shared.as_bytes())
+            .finalize();
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+}
+
+/// Binds the ciphertext to its sender and recipient, so it can't be replayed to someone else.
+fn associated_data(from: &str, to: &str) -> Vec<u8> {
+    format!("{from}\n{to}").into_bytes()
+}
+
+/// Generates a random bearer token, e.g. an API token of a bot.
+pub fn random_token(prefix: &str) -> String {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let hex: String = secret.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("{prefix}{hex}")
+}
+
+/// Hashes a token, so that a leaked server state doesn't leak the tokens.
+pub fn token_hash(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+/// Hashes a password with Argon2 and a random salt, the result holds both as a PHC string.
+pub fn password_hash(password: &str) -> Result<String, LibError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| LibError::CryptoError(format!("Password hashing failed: {e}")))
+}
+
+/// Checks the password against a hash made by `password_hash`.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Refuses a key file the group or other users can access.
+#[cfg(unix)]
+fn check_private(path: &Path, metadata: &Metadata) -> Result<(), LibError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(LibError::CryptoError(format!(
+            "Key file {path:?} is accessible by other users (mode {:o}), run chmod 600 on it",
+            mode & 0o777
+        )));
+    }
+    Ok(())
+}
+
+/// Accepts any key file, the permissions aren't checked outside of Unix.
+#[cfg(not(unix))]
+fn check_private(_: &Path, _: &Metadata) -> Result<(), LibError> {
+    Ok(())
+}
+
+/// Returns the SHA-256 of the content, e.g. the checksum of a file sent.
+pub fn sha256(content: &[u8]) -> [u8; 32] {
+    Sha256::digest(content).into()
+}
+
+/// Hashes the whole file piece by piece, so that large files aren't read into memory at once.
+pub async fn file_hash(path: &Path) -> Result<[u8; 32], LibError> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(hasher.finalize().into());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Returns a human-readable fingerprint of a public key for out-of-band verification.
+pub fn fingerprint(key: &[u8; 32]) -> String {
+    let digest = Sha256::digest(key);
+    digest[..16]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let message = alice
+            .encrypt("alice", "bob", &bob.public_key(), "secret")
+            .unwrap();
+        assert_eq!(bob.decrypt(&message).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_tampered_message_is_rejected() {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let mut message = alice
+            .encrypt("alice", "bob", &bob.public_key(), "secret")
+            .unwrap();
+        // Pretend the message came from someone else
+        if let MessageType::Encrypted { ref mut from, .. } = message {
+            *from = String::from("mallory");
+        }
+        assert!(bob.decrypt(&message).is_err());
+        // Nobody else can read it
+        let eve = Identity::generate();
+        assert!(eve.decrypt(&message).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("identity-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("alice.key");
+        let identity = Identity::load_or_generate(&path).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = Identity::load_or_generate(&path).await.unwrap();
+        assert_eq!(loaded.public_key(), identity.public_key());
+
+        // A key other users could read may have leaked
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(Identity::load_or_generate(&path).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_fingerprint_format() {
+        let print = fingerprint(&[7; 32]);
+        assert_eq!(print.len(), 39);
+        assert_eq!(print.split(' ').count(), 8);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::common::codec::WireFormat;
+use crate::common::{compression, LibError, MessageType};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use uuid::Uuid;
+
+/// Sender of the messages created by the server itself.
+pub const SERVER_SENDER: &str = "server";
+
+/// How far a message got on the recipient's side.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AckState {
+    /// The recipient's client received the message.
+    Delivered,
+    /// The message was shown to the recipient.
+    Read,
+}
+
+/// Message with the identity needed to acknowledge it.
+///
+/// Missing fields are filled in when decoding, so that hand-written JSON stays short.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    #[serde(default = "Uuid::new_v4")]
+    pub id: Uuid,
+    #[serde(default = "Utc::now")]
+    pub timestamp: DateTime<Utc>,
+    /// Always rewritten by the server, nobody can send on behalf of others.
+    #[serde(default)]
+    pub sender: String,
+    /// Set by the server for the messages of bots, so that clients can tell them apart.
+    #[serde(default)]
+    pub bot: bool,
+    pub message: MessageType,
+}
+
+impl Envelope {
+    /// Wraps the message into an envelope with a fresh ID.
+    pub fn new(sender: &str, message: MessageType) -> Self {
+        Envelope {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            sender: sender.to_string(),
+            bot: false,
+            message,
+        }
+    }
+
+    /// Returns whether the recipient is expected to acknowledge the message.
+    ///
+    /// Messages that aren't acknowledged are never stored for a later delivery either.
+    pub fn needs_ack(&self) -> bool {
+        !matches!(
+            self.message,
+            MessageType::Ack { .. }
+                | MessageType::Accepted { .. }
+                | MessageType::Quit
+                | MessageType::Edit { .. }
+                | MessageType::Delete { .. }
+                | MessageType::Presence { .. }
+                | MessageType::Typing { .. }
+        )
+    }
+
+    /// Builds the acknowledgment of this message sent back to its sender.
+    pub fn ack(&self, sender: &str, state: AckState) -> Envelope {
+        Envelope::new(
+            sender,
+            MessageType::Ack {
+                id: self.id,
+                to: self.sender.clone(),
+                state,
+            },
+        )
+    }
+
+    /// Receives an Envelope in the connection's wire format.
+    pub async fn receive<R: AsyncBufRead + Unpin>(
+        stream: &mut R,
+        wire: WireFormat,
+    ) -> Result<Self, LibError> {
+        let envelope: Envelope = wire.codec.read(stream).await?;
+        // Compressed messages are never passed to the caller
+        envelope.decompress()
+    }
+
+    /// Sends the Envelope in the connection's wire format.
+    pub async fn send<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        wire: WireFormat,
+    ) -> Result<(), LibError> {
+        if wire.compression {
+            if let Some(message) = compression::compress(&self.message)? {
+                let compressed = Envelope {
+                    sender: self.sender.clone(),
+                    message,
+                    ..*self
+                };
+                return wire.codec.write(stream, &compressed).await;
+            }
+        }
+        wire.codec.write(stream, self).await
+    }
+
+    /// Restores the original message if it was compressed.
+    pub fn decompress(self) -> Result<Self, LibError> {
+        Ok(Envelope {
+            message: compression::decompress(self.message)?,
+            ..self
+        })
+    }
+
+    /// Decodes an Envelope from a JSON string.
+    pub fn from_json(text: &str) -> Result<Self, LibError> {
+        Ok(serde_json::from_str(text)?)
+    }
+
+    /// Encodes the Envelope as a JSON string.
+    pub fn to_json(&self) -> Result<String, LibError> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Returns the ID prefix shown to the users, e.g. to refer to a message in `.edit`.
+pub fn short_id(id: &Uuid) -> String {
+    id.simple().to_string()[..8].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::codec::{Codec, ALL_CODECS};
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_round_trip_keeps_identity() {
+        for &codec in ALL_CODECS {
+            let wire = WireFormat {
+                codec,
+                compression: true,
+            };
+            let file = MessageType::file("log.csv", b"time,level\n".repeat(500));
+            let envelope = Envelope::new("alice", file);
+            let mut buffer = Vec::new();
+            envelope.send(&mut buffer, wire).await.unwrap();
+
+            let received = Envelope::receive(&mut BufReader::new(buffer.as_slice()), wire)
+                .await
+                .unwrap();
+            assert_eq!(received.id, envelope.id);
+            assert_eq!(received.timestamp, envelope.timestamp);
+            assert_eq!(received.sender, "alice");
+            assert!(matches!(received.message, MessageType::File { .. }));
+        }
+    }
+
+    #[test]
+    fn test_short_json_is_completed() {
+        let envelope = Envelope::from_json(r#"{"message":{"Text":"hi"}}"#).unwrap();
+        assert!(envelope.sender.is_empty());
+        assert!(envelope.needs_ack());
+
+        let ack = envelope.ack("bob", AckState::Read);
+        assert!(!ack.needs_ack());
+        assert!(Codec::Json
+            .encode(&ack)
+            .unwrap()
+            .ends_with(br#""state":"read"}}}"#));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::common::codec::Codec;
+use crate::common::envelope::SERVER_SENDER;
+use crate::common::{read_frame, write_frame, LibError};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Version of the wire protocol spoken by this crate.
+///
+/// Version 2 confirms the messages with `Accepted` and answers `.join` with `Joined`.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// Oldest protocol version the server still accepts, its clients are answered in it.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Handshake frames are tiny, anything bigger is not a handshake.
+const MAX_HANDSHAKE_LEN: usize = 4096;
+
+/// Optional capability of a peer.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Feature {
+    Compression,
+    Chunking,
+    Tls,
+    /// Feature introduced by a newer peer, never negotiated.
+    #[serde(other)]
+    Unknown,
+}
+
+/// First message sent by the client after connecting.
+///
+/// The handshake is encoded as JSON, so that peers of any version can decode it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub client_name: String,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    /// Codecs the client can speak, in the order of its preference.
+    #[serde(default)]
+    pub codecs: Vec<Codec>,
+    /// API token of a bot, people connect without one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Session token of a previous connection to resume.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<String>,
+    /// Password of the account the client logs into, names without an account need none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+/// Protocol settings agreed on by both peers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Welcome {
+    pub version: u16,
+    pub server_name: String,
+    #[serde(default)]
+    pub features: Vec<Feature>,
+    #[serde(default)]
+    pub codec: Codec,
+    /// Token to resume the session with after the connection breaks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<String>,
+}
+
+/// Server's answer to the Hello.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum HandshakeReply {
+    Welcome(Welcome),
+    Rejected(String),
+}
+
+impl Hello {
+    /// Constructs a Hello of the current protocol version.
+    pub fn new(client_name: &str, features: &[Feature], codecs: &[Codec]) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            client_name: client_name.to_string(),
+            features: features.to_vec(),
+            codecs: codecs.to_vec(),
+            token: None,
+            resume: None,
+            password: None,
+        }
+    }
+
+    /// Introduces the client as a bot authenticated with the token.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Logs into the account of the client name with the password.
+    pub fn with_password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Asks to resume the session of the token instead of starting a new one.
+    pub fn resuming(mut self, session_token: &str) -> Self {
+        self.resume = Some(session_token.to_string());
+        self
+    }
+
+    /// Checks the Hello against the server's capabilities and builds the reply.
+    pub fn negotiate(
+        &self,
+        server_features: &[Feature],
+        server_codecs: &[Codec],
+    ) -> HandshakeReply {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return HandshakeReply::Rejected(format!(
+                "Protocol version {} is no longer supported, the server accepts versions \
+                 {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}. Please upgrade the client.",
+                self.version
+            ));
+        }
+        if self.version > PROTOCOL_VERSION {
+            return HandshakeReply::Rejected(format!(
+                "Protocol version {} is newer than the server's version {PROTOCOL_VERSION}. \
+                 Please upgrade the server or use an older client.",
+                self.version
+            ));
+        }
+        if self.client_name.trim().is_empty() {
+            return HandshakeReply::Rejected(String::from("Client name can't be empty"));
+        }
+        if self.client_name == SERVER_SENDER {
+            return HandshakeReply::Rejected(format!("Client name {SERVER_SENDER} is reserved"));
+        }
+
+        // The client's most preferred codec wins, the server picks if the client has no preference
+        let codec = match self.codecs.is_empty() {
+            true => server_codecs.first(),
+            false => self
+                .codecs
+                .iter()
+                .find(|codec| server_codecs.contains(codec)),
+        };
+        let Some(&codec) = codec else {
+            let supported: Vec<String> = server_codecs.iter().map(Codec::to_string).collect();
+            return HandshakeReply::Rejected(format!(
+                "None of the codecs {:?} is supported, the server speaks {}",
+                self.codecs,
+                supported.join(", ")
+            ));
+        };
+
+        // Only the features both peers know are enabled
+        let features = self
+            .features
+            .iter()
+            .filter(|feature| **feature != Feature::Unknown && server_features.contains(feature))
+            .copied()
+            .collect();
+        HandshakeReply::Welcome(Welcome {
+            version: self.version,
+            server_name: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            features,
+            codec,
+            session_token: None,
+        })
+    }
+
+    /// Receives a Hello from the client.
+    pub async fn receive<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self, LibError> {
+        let frame = read_frame(stream, MAX_HANDSHAKE_LEN).await?;
+        Ok(serde_json::from_slice(&frame)?)
+    }
+
+    /// Sends the Hello to the server.
+    pub async fn send<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<(), LibError> {
+        write_frame(stream, &serde_json::to_vec(self)?).await
+    }
+}
+
+impl HandshakeReply {
+    /// Receives the reply from the server.
+    pub async fn receive<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Self, LibError> {
+        let frame = read_frame(stream, MAX_HANDSHAKE_LEN).await?;
+        Ok(serde_json::from_slice(&frame)?)
+    }
+
+    /// Sends the reply to the client.
+    pub async fn send<W: AsyncWrite + Unpin>(&self, stream: &mut W) -> Result<(), LibError> {
+        write_frame(stream, &serde_json::to_vec(self)?).await
+    }
+
+    /// Returns the agreed settings, or the reason of the rejection as an error.
+    pub fn into_welcome(self) -> Result<Welcome, LibError> {
+        match self {
+            HandshakeReply::Welcome(welcome) if welcome.version == PROTOCOL_VERSION => Ok(welcome),
+            HandshakeReply::Welcome(welcome) => Err(LibError::HandshakeRejected(format!(
+                "Server answered with protocol version {}, expected {PROTOCOL_VERSION}",
+                welcome.version
+            ))),
+            HandshakeReply::Rejected(reason) => Err(LibError::HandshakeRejected(reason)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::codec::ALL_CODECS;
+
+    fn welcome(reply: HandshakeReply) -> Welcome {
+        match reply {
+            HandshakeReply::Welcome(welcome) => welcome,
+            HandshakeReply::Rejected(reason) => panic!("Unexpected rejection: {reason}"),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_common_features() {
+        let hello = Hello::new("alice", &[Feature::Compression, Feature::Tls], &[]);
+        let welcome =
+            welcome(hello.negotiate(&[Feature::Compression, Feature::Chunking], ALL_CODECS));
+        assert_eq!(welcome.version, PROTOCOL_VERSION);
+        assert_eq!(welcome.features, vec![Feature::Compression]);
+    }
+
+    #[test]
+    fn test_reject_newer_version() {
+        let mut hello = Hello::new("alice", &[], &[]);
+        hello.version = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            hello.negotiate(&[], ALL_CODECS),
+            HandshakeReply::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_negotiate_older_version() {
+        let mut hello = Hello::new("alice", &[], &[]);
+        hello.version = MIN_PROTOCOL_VERSION;
+        let welcome = welcome(hello.negotiate(&[], ALL_CODECS));
+        assert_eq!(welcome.version, MIN_PROTOCOL_VERSION);
+        hello.version = MIN_PROTOCOL_VERSION - 1;
+        assert!(matches!(
+            hello.negotiate(&[], ALL_CODECS),
+            HandshakeReply::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn test_reject_invalid_name() {
+        for name in [" ", SERVER_SENDER] {
+            let hello = Hello::new(name, &[], &[]);
+            assert!(matches!(
+                hello.negotiate(&[], ALL_CODECS).into_welcome(),
+                Err(LibError::HandshakeRejected(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unknown_feature_is_ignored() {
+        let hello: Hello = serde_json::from_str(&format!(
+            r#"{{"version":{PROTOCOL_VERSION},"client_name":"bob","features":["compression","teleport"]}}"#
+        ))
+        .unwrap();
+        assert_eq!(hello.features, vec![Feature::Compression, Feature::Unknown]);
+        let welcome = welcome(hello.negotiate(&[Feature::Compression], ALL_CODECS));
+        assert_eq!(welcome.features, vec![Feature::Compression]);
+        assert_eq!(welcome.codec, Codec::Bincode);
+    }
+
+    #[test]
+    fn test_negotiate_codec() {
+        let hello = Hello::new("alice", &[], &[Codec::Unknown, Codec::MessagePack]);
+        let welcome = welcome(hello.negotiate(&[], ALL_CODECS));
+        assert_eq!(welcome.codec, Codec::MessagePack);
+        assert!(matches!(
+            hello.negotiate(&[], &[Codec::Json]),
+            HandshakeReply::Rejected(_)
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::common::LibError;
+use image::{ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Cursor;
+use std::path::Path;
+
+/// Format of a sent image, the ones the server sends and the clients keep as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl ImageKind {
+    /// Returns the format of a file with the extension, e.g. "jpg".
+    pub fn from_path(path: &Path) -> Option<Self> {
+        ImageFormat::from_path(path)
+            .ok()
+            .and_then(Self::from_format)
+    }
+
+    fn from_format(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Png => Some(ImageKind::Png),
+            ImageFormat::Jpeg => Some(ImageKind::Jpeg),
+            ImageFormat::Gif => Some(ImageKind::Gif),
+            ImageFormat::WebP => Some(ImageKind::Webp),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ImageKind::Png => "PNG",
+            ImageKind::Jpeg => "JPEG",
+            ImageKind::Gif => "GIF",
+            ImageKind::Webp => "WebP",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Format and size in pixels of an image, read from its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub format: ImageKind,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Reads the header of the image, without decoding the pixels.
+pub fn header(content: &[u8]) -> Result<Header, LibError> {
+    let reader = ImageReader::new(Cursor::new(content)).with_guessed_format()?;
+    let format = reader
+        .format()
+        .and_then(ImageKind::from_format)
+        .ok_or(LibError::UnsupportedImageFormat)?;
+    let (width, height) = reader.into_dimensions()?;
+    Ok(Header {
+        format,
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn test_header_is_read_without_the_pixels() {
+        let mut png = Vec::new();
+        ImageBuffer::from_pixel(3, 2, Rgb([0u8, 0, 0]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let expected = Header {
+            format: ImageKind::Png,
+            width: 3,
+            height: 2,
+        };
+        assert_eq!(header(&png).unwrap(), expected);
+        // Broken pixels aren't decoded, only the header is checked
+        let mut broken = png.clone();
+        let end = broken.len();
+        broken[end - 20..].fill(0);
+        assert!(image::load_from_memory(&broken).is_err());
+        assert_eq!(header(&broken).unwrap(), expected);
+        // Neither text nor formats the clients don't keep are accepted
+        assert!(matches!(
+            header(b"plain text"),
+            Err(LibError::UnsupportedImageFormat)
+        ));
+        let mut bmp = Vec::new();
+        ImageBuffer::from_pixel(1, 1, Rgb([0u8, 0, 0]))
+            .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+            .unwrap();
+        assert!(matches!(
+            header(&bmp),
+            Err(LibError::UnsupportedImageFormat)
+        ));
+        assert_eq!(
+            ImageKind::from_path(Path::new("photo.JPG")),
+            Some(ImageKind::Jpeg)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::Metadata;
+
+/// Kind of an entry of the served directory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Dir,
+    /// Symbolic link, never followed outside the served directory.
+    Link,
+    Other,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "directory",
+            EntryKind::Link => "link",
+            EntryKind::Other => "other",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// File or directory of the served directory, as listed by `.ls` and `.stat`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Name within the listed directory, or the path from the served directory for `.stat`.
+    pub name: String,
+    pub kind: EntryKind,
+    /// Size in bytes.
+    pub size: u64,
+    /// Time of the last change, `None` where the platform doesn't record it.
+    pub modified: Option<DateTime<Utc>>,
+}
+
+impl FileEntry {
+    /// Creates the entry from the metadata of the file, links are not followed.
+    pub fn new(name: &str, metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            EntryKind::Link
+        } else if file_type.is_dir() {
+            EntryKind::Dir
+        } else if file_type.is_file() {
+            EntryKind::File
+        } else {
+            EntryKind::Other
+        };
+        FileEntry {
+            name: name.to_string(),
+            kind,
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+        }
+    }
+
+    /// Returns the name, directories end with a slash.
+    fn display_name(&self) -> String {
+        match self.kind {
+            EntryKind::Dir => format!("{}/", self.name),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+/// Formats the time of the last change in the local time zone.
+fn modified(entry: &FileEntry) -> String {
+    match entry.modified {
+        Some(at) => at
+            .with_timezone(&Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => String::from("-"),
+    }
+}
+
+/// Renders the listing of the directory as a table with the sizes and modification times.
+pub fn table(dir: &str, entries: &[FileEntry]) -> String {
+    if entries.is_empty() {
+        return format!("{dir} is empty");
+    }
+    let names: Vec<String> = entries.iter().map(FileEntry::display_name).collect();
+    let sizes: Vec<String> = entries.iter().map(|entry| entry.size.to_string()).collect();
+    let name_width = names.iter().map(String::len).max().unwrap_or(0).max(4);
+    let size_width = sizes.iter().map(String::len).max().unwrap_or(0).max(4);
+    let mut lines = vec![
+        format!("Contents of {dir}:"),
+        format!("{:name_width$}  {:>size_width$}  MODIFIED", "NAME", "SIZE"),
+    ];
+    for ((name, size), entry) in names.iter().zip(&sizes).zip(entries) {
+        lines.push(format!(
+            "{name:name_width$}  {size:>size_width$}  {}",
+            modified(entry)
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Describes the metadata of a single entry, e.g. "/notes.txt: file, 12 bytes, modified ...".
+pub fn describe(entry: &FileEntry) -> String {
+    format!(
+        "{}: {}, {} bytes, modified {}",
+        entry.name,
+        entry.kind,
+        entry.size,
+        modified(entry)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_aligns_columns() {
+        let entries = [
+            FileEntry {
+                name: String::from("docs"),
+                kind: EntryKind::Dir,
+                size: 4096,
+                modified: None,
+            },
+            FileEntry {
+                name: String::from("a.txt"),
+                kind: EntryKind::File,
+                size: 12,
+                modified: None,
+            },
+        ];
+        assert_eq!(
+            table("/", &entries),
+            "Contents of /:\nNAME   SIZE  MODIFIED\ndocs/  4096  -\na.txt    12  -"
+        );
+        assert_eq!(table("/docs", &[]), "/docs is empty");
+    }
+}
@@ -1,22 +1,157 @@
-use chrono::Local;
+pub mod codec;
+pub mod compression;
+pub mod crypto;
+pub mod envelope;
+pub mod handshake;
+pub mod imaging;
+pub mod listing;
+pub mod presence;
+pub mod saving;
+
+use chrono::{DateTime, Utc};
+use codec::{Codec, WireFormat};
+use envelope::{AckState, Envelope};
 use image::{load_from_memory, ImageFormat};
+use imaging::ImageKind;
+use listing::FileEntry;
 use log::trace;
+use presence::Status;
+use saving::{Progress, SaveConfig, Saved};
 use serde::{Deserialize, Serialize};
-use std::ffi::OsStr;
+use std::env;
+use std::fmt;
+use std::io::ErrorKind;
 use std::net::Ipv4Addr;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
-use tokio::fs::{create_dir_all, read, File};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::fs::{metadata, read, remove_file, rename, File, OpenOptions};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, SeekFrom,
+};
+use tokio::sync::mpsc;
 use tokio::task;
+use uuid::Uuid;
+
+/// Largest frame accepted from the peer.
+pub const MAX_FRAME_LEN: usize = 512 * 1024 * 1024;
+/// Largest part of a file sent in one message, larger files are downloaded part by part.
+pub const FILE_PART_LEN: u64 = 4 * 1024 * 1024;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageType {
     Text(String),
-    Image(Vec<u8>),
-    File { name: String, content: Vec<u8> },
+    /// Image sent with the bytes of the original file.
+    Image {
+        /// Name of the original file, e.g. "rust.png".
+        name: String,
+        format: ImageKind,
+        /// Size in pixels, read from the header by the sender.
+        width: u32,
+        height: u32,
+        content: Vec<u8>,
+        /// SHA-256 of the content, computed by the sender.
+        sha256: [u8; 32],
+    },
+    File {
+        name: String,
+        content: Vec<u8>,
+        /// SHA-256 of the content, computed by the sender.
+        sha256: [u8; 32],
+    },
     Quit,
+    /// File or Image with zstd-compressed content, only sent when both peers negotiated it.
+    Compressed(Box<MessageType>),
+    /// Public key of a user, published by the client and handed out by the server.
+    PublicKey {
+        user: String,
+        key: [u8; 32],
+    },
+    /// Direct message only the recipient can decrypt, the server just relays it.
+    Encrypted {
+        from: String,
+        to: String,
+        sender_key: [u8; 32],
+        nonce: [u8; 12],
+        ciphertext: Vec<u8>,
+    },
+    /// Receipt for the message with the given ID, routed back to its sender.
+    Ack {
+        id: Uuid,
+        to: String,
+        state: AckState,
+    },
+    /// New text of a message, sent to everyone who may have displayed it.
+    Edit {
+        id: Uuid,
+        text: String,
+    },
+    /// Removal of a message, sent to everyone who may have displayed it.
+    Delete {
+        id: Uuid,
+    },
+    /// Availability of a user, with the time they left when offline. Never stored.
+    Presence {
+        user: String,
+        status: Status,
+        last_seen: Option<DateTime<Utc>>,
+    },
+    /// Signal that a user is typing in the room. Never stored.
+    Typing {
+        user: String,
+    },
+    /// Request the server refused, typed so that clients can react without parsing the text.
+    Error {
+        kind: RefusalKind,
+        message: String,
+    },
+    /// Entries of a directory the server serves, answering `.ls`.
+    Listing {
+        dir: String,
+        entries: Vec<FileEntry>,
+    },
+    /// Metadata of a file or directory the server serves, answering `.stat`.
+    Stat(FileEntry),
+    /// Part of a file too large for one message, or requested from an offset.
+    FilePart {
+        name: String,
+        /// Position of the content in the file.
+        offset: u64,
+        /// Size of the whole file.
+        total: u64,
+        content: Vec<u8>,
+        /// SHA-256 of the whole file, sent with the last part.
+        sha256: Option<[u8; 32]>,
+    },
+    /// ID the server gave the message the client sent with the ID `request`. Never stored.
+    ///
+    /// Sent since protocol version 2, the variants of version 1 keep their place before it.
+    Accepted {
+        request: Uuid,
+        id: Uuid,
+    },
+    /// Room the session moved to, answering `.join`. Sent since protocol version 2.
+    Joined {
+        room: String,
+    },
+}
+
+/// Reason the server refused a request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefusalKind {
+    /// No command of the name exists.
+    UnknownCommand,
+    /// An argument is missing, the message shows the usage.
+    Usage,
+    /// An argument couldn't be used, e.g. a value that couldn't be parsed.
+    InvalidArgument,
+    /// The user isn't allowed to do that.
+    NotPermitted,
+}
+
+/// Answers a file or image that couldn't be sent, e.g. a path that doesn't exist.
+fn invalid(e: LibError) -> MessageType {
+    MessageType::refusal(RefusalKind::InvalidArgument, e.to_string())
 }
 
 /// Custom error type for the crate.
@@ -28,6 +163,8 @@ pub enum LibError {
     ImageSavingError(#[from] image::ImageError),
     #[error("Serialization error: {0}")]
     SerializationError(#[from] bincode::Error),
+    #[error("JSON error: {0}")]
+    JsonError(#[from] serde_json::Error),
     #[error("I/O error: {0}")]
     IoError(#[from] std::io::Error),
     #[error("I/O error: Connection closed")]
@@ -38,28 +175,93 @@ pub enum LibError {
     PortParsingError(#[from] std::num::ParseIntError),
     #[error("Error reading image: {0}")]
     ImageReadingError(String),
-    #[error("Wrong image extension. Only PNG images are supported.")]
+    #[error("Wrong image extension. Only PNG, JPEG, GIF and WebP images are supported.")]
     WrongImageExtension,
+    #[error("Unsupported image format. Only PNG, JPEG, GIF and WebP images are supported.")]
+    UnsupportedImageFormat,
+    #[error("Image {0} doesn't match its header")]
+    ImageHeaderMismatch(String),
     #[error("Error reading file: {0}")]
     FileReadingError(String),
     #[error("Error parsing file name")]
     FileNameError,
+    #[error("Frame of {0} bytes exceeds the size limit")]
+    FrameTooLarge(usize),
+    #[error("Handshake rejected: {0}")]
+    HandshakeRejected(String),
+    #[error("MessagePack encoding error: {0}")]
+    MessagePackEncodeError(#[from] rmp_serde::encode::Error),
+    #[error("MessagePack decoding error: {0}")]
+    MessagePackDecodeError(#[from] rmp_serde::decode::Error),
+    #[error("Unsupported codec. Use one of bincode, json or msgpack.")]
+    UnsupportedCodec,
+    #[error("Encryption error: {0}")]
+    CryptoError(String),
+    #[error("Unknown status {0}. Use one of online, away or busy.")]
+    UnknownStatus(String),
+    #[error("Missing path of the Unix socket after --unix")]
+    MissingSocketPath,
+    #[error("Missing value after {0}")]
+    MissingOptionValue(String),
+    #[error("Offset {0} is beyond the end of the file")]
+    OffsetOutOfRange(u64),
+    #[error("Part of {name} at offset {offset} doesn't follow the {saved} bytes saved")]
+    UnexpectedPart {
+        name: String,
+        offset: u64,
+        saved: u64,
+    },
+    #[error("Download of {0} is corrupted, its SHA-256 doesn't match")]
+    DownloadCorrupted(String),
+    #[error("Checksum of {0} doesn't match its content, it was corrupted on the way")]
+    ChecksumMismatch(String),
+    #[error("Unknown overwrite policy {0}. Use one of overwrite, rename, skip or ask.")]
+    UnknownOverwrite(String),
+    #[error("No directory for the identity keys, set KEYS_DIR or HOME")]
+    NoKeysDir,
 }
 
 impl MessageType {
+    /// Constructs a MessageType::File with the checksum of the content.
+    pub fn file(name: &str, content: Vec<u8>) -> Self {
+        MessageType::File {
+            name: name.to_string(),
+            sha256: crypto::sha256(&content),
+            content,
+        }
+    }
+
+    /// Constructs a MessageType::Image with the format and size read from the header of the
+    /// content, and its checksum.
+    pub fn image(name: &str, content: Vec<u8>) -> Result<Self, LibError> {
+        let header = imaging::header(&content)?;
+        Ok(MessageType::Image {
+            name: name.to_string(),
+            format: header.format,
+            width: header.width,
+            height: header.height,
+            sha256: crypto::sha256(&content),
+            content,
+        })
+    }
+
     /// Constructs a MessageType::Image from a given image file path.
     pub async fn from_image(image_path: &Path) -> Self {
-        // Check for png extension
-        if image_path.extension() != Some(OsStr::new("png")) {
-            return MessageType::Text(LibError::WrongImageExtension.to_string());
-        }
-        // Read image content
-        match read(image_path).await {
-            Ok(content) => MessageType::Image(content),
-            Err(_) => MessageType::Text(
-                LibError::ImageReadingError(format!("{:?}", image_path)).to_string(),
-            ),
+        // Check for a supported extension
+        if ImageKind::from_path(image_path).is_none() {
+            return invalid(LibError::WrongImageExtension);
         }
+        let Some(name) = image_path.file_name() else {
+            return invalid(LibError::FileNameError);
+        };
+        // Read image content, it's sent as it is
+        let image = match read(image_path).await {
+            Ok(content) => MessageType::image(&name.to_string_lossy(), content),
+            Err(e) => Err(LibError::IoError(e)),
+        };
+        image.unwrap_or_else(|e| {
+            invalid(LibError::ImageReadingError(format!("{image_path:?}: {e}")))
+        })
     }
 
     /// Constructs a MessageType::File from a given file path.
@@ -67,15 +269,87 @@ impl MessageType {
         // Read file name
         let name = match file_path.file_name() {
             Some(os_name) => os_name.to_string_lossy().into_owned(),
-            None => return MessageType::Text(LibError::FileNameError.to_string()),
+            None => return invalid(LibError::FileNameError),
         };
 
         // Read file content
         match read(file_path).await {
-            Ok(content) => MessageType::File { name, content },
-            Err(_) => MessageType::Text(
-                LibError::FileReadingError(format!("{:?}", file_path)).to_string(),
-            ),
+            Ok(content) => MessageType::file(&name, content),
+            Err(_) => invalid(LibError::FileReadingError(format!("{:?}", file_path))),
+        }
+    }
+
+    /// Constructs a MessageType::File, or a FilePart starting at the offset if the file is too large
+    /// for one message or the offset isn't 0.
+    pub async fn from_file_range(file_path: &Path, offset: u64) -> Self {
+        let reading_error = || invalid(LibError::FileReadingError(format!("{:?}", file_path)));
+        let name = match file_path.file_name() {
+            Some(os_name) => os_name.to_string_lossy().into_owned(),
+            None => return invalid(LibError::FileNameError),
+        };
+        let Ok(mut file) = File::open(file_path).await else {
+            return reading_error();
+        };
+        let Ok(total) = file.metadata().await.map(|metadata| metadata.len()) else {
+            return reading_error();
+        };
+        if offset == 0 && total <= FILE_PART_LEN {
+            return MessageType::from_file(file_path).await;
+        }
+        if offset > total {
+            return invalid(LibError::OffsetOutOfRange(offset));
+        }
+
+        // Read the part following the offset
+        let mut content = Vec::new();
+        let read = match file.seek(SeekFrom::Start(offset)).await {
+            Ok(_) => {
+                (&mut file)
+                    .take(FILE_PART_LEN)
+                    .read_to_end(&mut content)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if read.is_err() {
+            return reading_error();
+        }
+        // The last part carries the hash of the whole file, so that the client can verify it
+        let sha256 = if offset + content.len() as u64 >= total {
+            match crypto::file_hash(file_path).await {
+                Ok(hash) => Some(hash),
+                Err(_) => return reading_error(),
+            }
+        } else {
+            None
+        };
+        MessageType::FilePart {
+            name,
+            offset,
+            total,
+            content,
+            sha256,
+        }
+    }
+
+    /// Constructs the typed answer to a refused request.
+    pub fn refusal(kind: RefusalKind, message: impl Into<String>) -> Self {
+        MessageType::Error {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Translates an answer of the server for a client of an older protocol version.
+    ///
+    /// Returns `None` if the client wouldn't understand the message and doesn't need it.
+    pub fn downgrade(self, version: u16) -> Option<Self> {
+        match self {
+            MessageType::Accepted { .. } if version < 2 => None,
+            MessageType::Joined { room } if version < 2 => {
+                Some(MessageType::Text(format!("Joined room {room}")))
+            }
+            message => Some(message),
         }
     }
 
@@ -84,93 +358,227 @@ impl MessageType {
         MessageType::Text(text.to_string())
     }
 
-    /// Saves an Image message to a file.
-    pub async fn to_image(&self) -> Result<(), LibError> {
-        if let MessageType::Image(ref content) = *self {
-            // Create the images directory if it doesn't exist
-            create_dir_all("images").await?;
-            // Generate a timestamped file name
-            let name = format!(
-                "{}.png",
-                Local::now().format("%Y-%m-%d_%H-%M-%S").to_string()
-            );
-            // Create a PathBuf for the image path
-            let path: PathBuf = PathBuf::from("images").join(name);
+    /// Checks that the content of a File or Image message matches the checksum of its sender.
+    pub fn verify(&self) -> Result<(), LibError> {
+        let (what, content, sha256) = match self {
+            MessageType::File {
+                name,
+                content,
+                sha256,
+            } => (name.as_str(), content, sha256),
+            MessageType::Image {
+                name,
+                content,
+                sha256,
+                ..
+            } => (name.as_str(), content, sha256),
+            _ => return Err(LibError::WrongMessageType),
+        };
+        if crypto::sha256(content) != *sha256 {
+            return Err(LibError::ChecksumMismatch(what.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Returns the path a File, FilePart or Image is saved to unless the name is taken, only the
+    /// last component of the name sent is kept so that nothing lands outside the directory.
+    pub fn save_path(&self, saving: &SaveConfig) -> Option<PathBuf> {
+        let (dir, name) = match self {
+            MessageType::File { name, .. } | MessageType::FilePart { name, .. } => {
+                (saving.files_dir(), PathBuf::from(name))
+            }
+            // Re-encoded images get the extension of their new format
+            MessageType::Image { name, .. } if saving.to_png => {
+                (saving.images_dir(), Path::new(name).with_extension("png"))
+            }
+            MessageType::Image { name, .. } => (saving.images_dir(), PathBuf::from(name)),
+            _ => return None,
+        };
+        Some(dir.join(name.file_name()?))
+    }
 
-            // Create and save the image file
-            File::create(&path).await?;
+    /// Saves an Image message to the images directory, once its checksum is verified and its
+    /// header matches the format and size it was sent with.
+    ///
+    /// The original bytes are kept, unless the settings ask for re-encoding as PNG.
+    pub async fn to_image(&self, saving: &SaveConfig) -> Result<Saved, LibError> {
+        if let MessageType::Image {
+            ref name,
+            format,
+            width,
+            height,
+            ref content,
+            ..
+        } = *self
+        {
+            self.verify()?;
+            let header = imaging::header(content)?;
+            if (header.format, header.width, header.height) != (format, width, height) {
+                return Err(LibError::ImageHeaderMismatch(name.clone()));
+            }
+            let path = self.save_path(saving).ok_or(LibError::FileNameError)?;
+            let saved = saving.target(&path).await?;
+            let Saved::Written(ref path) = saved else {
+                return Ok(saved);
+            };
 
+            if !saving.to_png {
+                let mut file = File::create(path).await?;
+                file.write_all(content).await?;
+                return Ok(saved);
+            }
             task::block_in_place(move || {
-                // Save the image data to a file synchronously
+                // Decode and re-encode the image synchronously
                 let img = load_from_memory(content)?;
                 img.save_with_format(path, ImageFormat::Png)?;
-                Ok(())
-            })
+                Ok::<_, LibError>(())
+            })?;
+            Ok(saved)
         } else {
             Err(LibError::WrongMessageType)
         }
     }
 
-    /// Saves a File message to a file.
-    pub async fn to_file(&self) -> Result<(), LibError> {
-        if let MessageType::File {
-            ref name,
-            ref content,
-        } = *self
-        {
-            // Create the files directory if it doesn't exist
-            create_dir_all("files").await?;
-            // Create a PathBuf for the file path
-            let path: PathBuf = PathBuf::from("files").join(name);
+    /// Saves a File message to the files directory, once its checksum is verified.
+    pub async fn to_file(&self, saving: &SaveConfig) -> Result<Saved, LibError> {
+        if let MessageType::File { ref content, .. } = *self {
+            self.verify()?;
+            let path = self.save_path(saving).ok_or(LibError::FileNameError)?;
+            let saved = saving.target(&path).await?;
+            let Saved::Written(ref path) = saved else {
+                return Ok(saved);
+            };
 
             // Create and write the file contents
             let mut file = File::create(path).await?;
             file.write_all(content).await?;
-            Ok(())
+            Ok(saved)
         } else {
             Err(LibError::WrongMessageType)
         }
     }
 
-    /// Receives a MessageType from tcp stream.
-    pub async fn receive(stream: &mut TcpStream) -> Result<Self, LibError> {
-        let mut buffer = Vec::new();
+    /// Appends a FilePart message to the partially downloaded file, returns the offset of the
+    /// next part, or where the file was saved once the whole of it arrived and its SHA-256 matches.
+    pub async fn save_part(&self, saving: &SaveConfig) -> Result<Progress, LibError> {
+        let MessageType::FilePart {
+            ref name,
+            offset,
+            total,
+            ref content,
+            sha256,
+        } = *self
+        else {
+            return Err(LibError::WrongMessageType);
+        };
+        let path = self.save_path(saving).ok_or(LibError::FileNameError)?;
+        // A file that would be skipped isn't downloaded at all
+        if offset == 0 {
+            if let skipped @ Saved::Skipped(_) = saving.target(&path).await? {
+                return Ok(Progress::Done(skipped));
+            }
+        }
+        let partial = part_path(saving, &file_name(&path));
+        let saved = match metadata(&partial).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(LibError::IoError(e)),
+        };
+        // Parts may be sent again, but none may be skipped
+        if offset > saved {
+            return Err(LibError::UnexpectedPart {
+                name: name.clone(),
+                offset,
+                saved,
+            });
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&partial)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(content).await?;
+        file.flush().await?;
 
-        loop {
-            // Read data from the stream into a temporary buffer
-            let mut temp_buffer = [0; 100];
-            let bytes_read = match stream.read(&mut temp_buffer).await {
-                Ok(0) => return Err(LibError::ConnectionClosed),
-                Ok(n) => n,
-                Err(e) => return Err(LibError::IoError(e)),
-            };
+        let end = offset + content.len() as u64;
+        if end < total {
+            return Ok(Progress::Next(end));
+        }
+        // A corrupted download can't be continued, the next request starts over
+        if sha256 != Some(crypto::file_hash(&partial).await?) {
+            remove_file(&partial).await?;
+            return Err(LibError::DownloadCorrupted(name.clone()));
+        }
+        let saved = saving.target(&path).await?;
+        match saved {
+            Saved::Written(ref path) => rename(&partial, path).await?,
+            Saved::Skipped(_) => remove_file(&partial).await?,
+        }
+        Ok(Progress::Done(saved))
+    }
+}
 
-            // Append the read data to the main buffer
-            buffer.extend_from_slice(&temp_buffer[..bytes_read]);
-
-            // Attempt to deserialize the buffer
-            match bincode::deserialize::<MessageType>(&buffer) {
-                Ok(message) => {
-                    // Return the successfully deserialized message
-                    return Ok(message);
-                }
-                Err(_) => {
-                    // If deserialization fails, it might be due to incomplete data,
-                    // so continue reading more data from the stream
-                    trace!("Failed to deserialize message, continue reading");
-                }
-            }
+/// Returns the name of the file at the path.
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Returns the path of the partially downloaded file.
+pub fn part_path(saving: &SaveConfig, name: &str) -> PathBuf {
+    saving.files_dir().join(format!("{name}.part"))
+}
+
+/// Receives messages from the peer and forwards them until the connection fails.
+pub async fn receive_loop<R: AsyncRead + Unpin>(
+    reader: R,
+    wire: WireFormat,
+    tx: mpsc::Sender<Result<Envelope, LibError>>,
+) {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let message = Envelope::receive(&mut reader, wire).await;
+        let failed = message.is_err();
+        if tx.send(message).await.is_err() || failed {
+            return;
         }
     }
+}
 
-    /// Sends MessageTpe to tcp stream.
-    pub async fn send(&self, stream: &mut TcpStream) -> Result<(), LibError> {
-        // Serialize the message
-        let encoded: Vec<u8> = bincode::serialize(self)?;
-        // Write the serialized message to the stream
-        stream.write_all(&encoded).await?;
-        Ok(())
+/// Reads one length-prefixed frame, so that back-to-back frames stay intact.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    max_len: usize,
+) -> Result<Vec<u8>, LibError> {
+    // Read the length prefix of the frame
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(LibError::ConnectionClosed),
+        Err(e) => return Err(LibError::IoError(e)),
+    };
+    // Refuse garbage prefixes instead of allocating them
+    if len > max_len {
+        return Err(LibError::FrameTooLarge(len));
     }
+    trace!("Receiving frame of {len} bytes");
+
+    let mut buffer = vec![0; len];
+    stream.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+/// Writes one length-prefixed frame.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    payload: &[u8],
+) -> Result<(), LibError> {
+    stream.write_u32(payload.len() as u32).await?;
+    stream.write_all(payload).await?;
+    Ok(())
 }
 
 pub fn parse_addr(args: &[String]) -> Result<(Ipv4Addr, u16), LibError> {
@@ -182,7 +590,7 @@ pub fn parse_addr(args: &[String]) -> Result<(Ipv4Addr, u16), LibError> {
     trace!("Number of arguments: {}, arguments: {:?}", args.len(), args);
 
     // Update port and IP address based on provided arguments
-    if args.len() > 0 {
+    if !args.is_empty() {
         port = args[0].parse::<u16>()?;
     } else {
         trace!("Using default port number")
@@ -194,3 +602,106 @@ pub fn parse_addr(args: &[String]) -> Result<(Ipv4Addr, u16), LibError> {
     }
     Ok((ip, port))
 }
+
+/// Server address a client connects to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(Ipv4Addr, u16),
+    /// Unix socket of a server on the same host.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(ip, port) => write!(f, "{ip}:{port}"),
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Returns the server endpoint from the arguments, `--unix <path>` replaces the port and IP.
+pub fn parse_endpoint(args: &[String]) -> Result<Endpoint, LibError> {
+    if args.first().map(String::as_str) != Some("--unix") {
+        let (ip, port) = parse_addr(args)?;
+        return Ok(Endpoint::Tcp(ip, port));
+    }
+    match args.get(1) {
+        Some(path) => Ok(Endpoint::Unix(PathBuf::from(path))),
+        None => Err(LibError::MissingSocketPath),
+    }
+}
+
+/// Removes `--unix <path>` from the arguments and returns the path.
+pub fn take_unix_path(args: &mut Vec<String>) -> Result<Option<PathBuf>, LibError> {
+    match take_option(args, "--unix") {
+        Err(LibError::MissingOptionValue(_)) => Err(LibError::MissingSocketPath),
+        taken => Ok(taken?.map(PathBuf::from)),
+    }
+}
+
+/// Removes a named option, e.g. `--data-dir <path>`, from the arguments and returns its value.
+pub fn take_option(args: &mut Vec<String>, option: &str) -> Result<Option<String>, LibError> {
+    let Some(index) = args.iter().position(|arg| arg == option) else {
+        return Ok(None);
+    };
+    if index + 1 >= args.len() {
+        return Err(LibError::MissingOptionValue(option.to_string()));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
+}
+
+/// Returns the client name from the arguments, defaults to the current user.
+pub fn parse_name(args: &[String]) -> String {
+    match args.get(2) {
+        Some(name) => name.clone(),
+        None => {
+            trace!("Using the user name as the client name");
+            env::var("USER").unwrap_or_else(|_| String::from("anonymous"))
+        }
+    }
+}
+
+/// Returns the codec requested in the arguments, defaults to bincode.
+pub fn parse_codec(args: &[String]) -> Result<Codec, LibError> {
+    match args.get(3) {
+        Some(codec) => codec.parse(),
+        None => {
+            trace!("Using the default codec");
+            Ok(Codec::default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tampered_content_is_refused() {
+        let mut file = MessageType::file("a.txt", b"hello".to_vec());
+        file.verify().unwrap();
+        if let MessageType::File { content, .. } = &mut file {
+            content[0] = b'j';
+        }
+        // Nothing is written when the checksum doesn't match
+        assert!(matches!(
+            file.to_file(&SaveConfig::default()).await,
+            Err(LibError::ChecksumMismatch(name)) if name == "a.txt"
+        ));
+        let image = MessageType::Image {
+            name: String::from("rust.png"),
+            format: ImageKind::Png,
+            width: 1,
+            height: 1,
+            content: vec![0x89, b'P', b'N', b'G'],
+            sha256: [0; 32],
+        };
+        assert!(matches!(
+            image.to_image(&SaveConfig::default()).await,
+            Err(LibError::ChecksumMismatch(_))
+        ));
+    }
+}
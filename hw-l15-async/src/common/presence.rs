@@ -0,0 +1,65 @@
+use crate::common::LibError;
+use chrono::{DateTime, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Availability of a user shown to the others in the room.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    /// Set by the server when the last session of the user closes.
+    Offline,
+}
+
+impl FromStr for Status {
+    type Err = LibError;
+
+    fn from_str(input: &str) -> Result<Status, Self::Err> {
+        // Users can't pretend to be offline, the server announces it
+        match input.to_lowercase().as_str() {
+            "online" => Ok(Status::Online),
+            "away" => Ok(Status::Away),
+            "busy" => Ok(Status::Busy),
+            _ => Err(LibError::UnknownStatus(input.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Status::Online => "online",
+            Status::Away => "away",
+            Status::Busy => "busy",
+            Status::Offline => "offline",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Describes the presence of a user, e.g. "alice is away".
+pub fn describe(user: &str, status: Status, last_seen: Option<DateTime<Utc>>) -> String {
+    match last_seen {
+        Some(at) => format!(
+            "{user} is {status}, last seen {}",
+            at.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+        ),
+        None => format!("{user} is {status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!("Away".parse::<Status>().unwrap(), Status::Away);
+        assert!("offline".parse::<Status>().is_err());
+    }
+}
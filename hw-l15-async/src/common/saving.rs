@@ -0,0 +1,206 @@
+use crate::common::LibError;
+use std::env;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::{self, Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs::{create_dir_all, symlink_metadata};
+
+/// What to do with a received file whose name is already taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Replaces the existing file.
+    Overwrite,
+    /// Saves the file under the first free name, e.g. "notes (1).txt".
+    #[default]
+    Rename,
+    /// Keeps the existing file and drops the received one.
+    Skip,
+    /// Asks the user, only the interactive client can; elsewhere the same as Rename.
+    Ask,
+}
+
+impl FromStr for Overwrite {
+    type Err = LibError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "overwrite" => Ok(Overwrite::Overwrite),
+            "rename" => Ok(Overwrite::Rename),
+            "skip" => Ok(Overwrite::Skip),
+            "ask" => Ok(Overwrite::Ask),
+            _ => Err(LibError::UnknownOverwrite(s.to_string())),
+        }
+    }
+}
+
+/// Where the received files and images are saved, and how names already taken are handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveConfig {
+    /// Directory the subdirectories are created in.
+    pub root: PathBuf,
+    /// Subdirectory of the files, relative to the root.
+    pub files: PathBuf,
+    /// Subdirectory of the images, relative to the root.
+    pub images: PathBuf,
+    pub overwrite: Overwrite,
+    /// Re-encodes the images as PNG instead of keeping the bytes they were sent with.
+    pub to_png: bool,
+}
+
+impl Default for SaveConfig {
+    /// Saves to `files/` and `images/` under the working directory as received, renaming on
+    /// conflicts.
+    fn default() -> Self {
+        SaveConfig {
+            root: PathBuf::from("."),
+            files: PathBuf::from("files"),
+            images: PathBuf::from("images"),
+            overwrite: Overwrite::default(),
+            to_png: false,
+        }
+    }
+}
+
+impl SaveConfig {
+    /// Reads the root from `DOWNLOAD_DIR` and the policy from `DOWNLOAD_OVERWRITE`, if set, and
+    /// re-encodes the images if `DOWNLOAD_AS_PNG` is `1` or `true`.
+    pub fn from_env() -> Result<Self, LibError> {
+        let mut config = SaveConfig::default();
+        if let Ok(root) = env::var("DOWNLOAD_DIR") {
+            config.root = PathBuf::from(root);
+        }
+        if let Ok(overwrite) = env::var("DOWNLOAD_OVERWRITE") {
+            config.overwrite = overwrite.parse()?;
+        }
+        config.to_png = env::var("DOWNLOAD_AS_PNG").is_ok_and(|png| png == "1" || png == "true");
+        Ok(config)
+    }
+
+    /// Returns the same directories with another policy.
+    pub fn with_overwrite(&self, overwrite: Overwrite) -> Self {
+        SaveConfig {
+            overwrite,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the directory of the received files.
+    pub fn files_dir(&self) -> PathBuf {
+        self.root.join(&self.files)
+    }
+
+    /// Returns the directory of the received images.
+    pub fn images_dir(&self) -> PathBuf {
+        self.root.join(&self.images)
+    }
+
+    /// Creates the directory of the path and returns the absolute path to save the file to,
+    /// another one if the name is taken, or `Skipped` if the policy keeps the existing file.
+    pub async fn target(&self, path: &Path) -> Result<Saved, LibError> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir).await?;
+        }
+        let path = path::absolute(path)?;
+        if !exists(&path).await? {
+            return Ok(Saved::Written(path));
+        }
+        match self.overwrite {
+            Overwrite::Overwrite => Ok(Saved::Written(path)),
+            Overwrite::Skip => Ok(Saved::Skipped(path)),
+            Overwrite::Rename | Overwrite::Ask => {
+                let name = Path::new(path.file_name().unwrap_or_default());
+                let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+                let extension = name
+                    .extension()
+                    .map(|extension| format!(".{}", extension.to_string_lossy()))
+                    .unwrap_or_default();
+                for n in 1.. {
+                    let renamed = path.with_file_name(format!("{stem} ({n}){extension}"));
+                    if !exists(&renamed).await? {
+                        return Ok(Saved::Written(renamed));
+                    }
+                }
+                unreachable!("Ran out of file names")
+            }
+        }
+    }
+}
+
+/// Checks whether anything exists at the path, without following links.
+async fn exists(path: &Path) -> Result<bool, LibError> {
+    match symlink_metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(LibError::IoError(e)),
+    }
+}
+
+/// Returns whether a file exists at the path, e.g. to ask before replacing it.
+pub async fn is_taken(path: &Path) -> bool {
+    exists(path).await.unwrap_or(false)
+}
+
+/// Where a received file ended up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Saved {
+    /// Written to the absolute path.
+    Written(PathBuf),
+    /// Dropped, the file at the absolute path was kept.
+    Skipped(PathBuf),
+}
+
+impl fmt::Display for Saved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Saved::Written(path) => write!(f, "saved to {}", path.display()),
+            Saved::Skipped(path) => write!(f, "skipped, {} already exists", path.display()),
+        }
+    }
+}
+
+/// How far the download of a file sent part by part got.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// Offset of the next part to request.
+    Next(u64),
+    /// The whole file arrived and its SHA-256 matches, or it was skipped.
+    Done(Saved),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_taken_names_follow_the_policy() {
+        let root = env::temp_dir().join(format!("saving-{}", Uuid::new_v4()));
+        let config = SaveConfig {
+            root: root.clone(),
+            ..SaveConfig::default()
+        };
+        let dir = config.files_dir();
+        let notes = dir.join("notes.txt");
+        let Saved::Written(free) = config.target(&notes).await.unwrap() else {
+            panic!("Expected a free name");
+        };
+        assert!(free.is_absolute() && free.ends_with("files/notes.txt"));
+        std::fs::write(&free, b"kept").unwrap();
+
+        let renamed = config.target(&notes).await.unwrap();
+        assert_eq!(renamed, Saved::Written(dir.join("notes (1).txt")));
+        let overwrite = config.with_overwrite(Overwrite::Overwrite);
+        assert_eq!(
+            overwrite.target(&notes).await.unwrap(),
+            Saved::Written(free.clone())
+        );
+        let skip = config.with_overwrite(Overwrite::Skip);
+        assert_eq!(skip.target(&notes).await.unwrap(), Saved::Skipped(free));
+        assert!(matches!(
+            "replace".parse::<Overwrite>(),
+            Err(LibError::UnknownOverwrite(_))
+        ));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 
 pub mod client;
+pub mod loadgen;
 pub mod server;
 pub mod common;
\ No newline at end of file
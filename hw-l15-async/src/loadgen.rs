@@ -0,0 +1,447 @@
+use crate::client::{create_client, handshake};
+use crate::common::codec::{Codec, WireFormat};
+use crate::common::envelope::{AckState, Envelope, SERVER_SENDER};
+use crate::common::{receive_loop, MessageType};
+use anyhow::{bail, Context, Error, Result};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+/// Time the clients wait for the outstanding responses after they stop sending.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request sent by the simulated clients.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestKind {
+    Text,
+    File,
+    Image,
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RequestKind::Text => "text",
+            RequestKind::File => "file",
+            RequestKind::Image => "image",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Share of every request kind in the load, e.g. `text=8,file=1,image=1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mix {
+    weights: Vec<(RequestKind, u32)>,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix {
+            weights: vec![
+                (RequestKind::Text, 90),
+                (RequestKind::File, 5),
+                (RequestKind::Image, 5),
+            ],
+        }
+    }
+}
+
+impl FromStr for Mix {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let mut weights = Vec::new();
+        for part in input.split(',') {
+            let Some((kind, weight)) = part.trim().split_once('=') else {
+                bail!("Expected <kind>=<weight>, got {part:?}");
+            };
+            let kind = match kind {
+                "text" => RequestKind::Text,
+                "file" => RequestKind::File,
+                "image" => RequestKind::Image,
+                _ => bail!("Unknown request kind {kind}. Use one of text, file or image."),
+            };
+            let weight = weight.parse().context("Failed to parse weight")?;
+            weights.push((kind, weight));
+        }
+        if weights.iter().all(|&(_, weight)| weight == 0) {
+            bail!("At least one request kind needs a non-zero weight");
+        }
+        Ok(Mix { weights })
+    }
+}
+
+impl Mix {
+    /// Returns the request kinds in the mixed order, spreading every kind evenly.
+    fn kinds(&self) -> impl Iterator<Item = RequestKind> + '_ {
+        // Smooth weighted round-robin, so that a large weight doesn't send long bursts of one kind
+        let total: i64 = self.weights.iter().map(|&(_, weight)| weight as i64).sum();
+        let mut current = vec![0i64; self.weights.len()];
+        std::iter::repeat_with(move || {
+            for (current, &(_, weight)) in current.iter_mut().zip(&self.weights) {
+                *current += weight as i64;
+            }
+            let (index, _) = current
+                .iter()
+                .enumerate()
+                .max_by_key(|&(index, current)| (*current, std::cmp::Reverse(index)))
+                .unwrap();
+            current[index] -= total;
+            self.weights[index].0
+        })
+    }
+}
+
+/// Settings of a load test.
+#[derive(Debug, Clone)]
+pub struct LoadConfig {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub codec: Codec,
+    /// Number of simulated clients connected at the same time.
+    pub clients: usize,
+    /// Requests per second sent by all the clients together.
+    pub rate: f64,
+    /// Time the clients keep sending.
+    pub duration: Duration,
+    pub mix: Mix,
+    /// File requested by the `.file` requests, as seen by the server.
+    pub file: PathBuf,
+    /// Image requested by the `.image` requests, as seen by the server.
+    pub image: PathBuf,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        LoadConfig {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 11111,
+            codec: Codec::default(),
+            clients: 10,
+            rate: 100.0,
+            duration: Duration::from_secs(10),
+            mix: Mix::default(),
+            file: PathBuf::from("file.txt"),
+            image: PathBuf::from("rust.png"),
+        }
+    }
+}
+
+/// Latency percentiles in milliseconds.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Latency {
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+/// Results of a single request kind.
+#[derive(Serialize, Debug, Clone)]
+pub struct KindReport {
+    pub kind: RequestKind,
+    pub sent: u64,
+    pub completed: u64,
+    pub latency: Latency,
+}
+
+/// Results of a load test.
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub clients: usize,
+    pub elapsed_secs: f64,
+    pub sent: u64,
+    pub completed: u64,
+    /// Completed requests per second.
+    pub throughput: f64,
+    pub latency: Latency,
+    pub kinds: Vec<KindReport>,
+    /// Number of failures by their reason.
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// Requests and failures of a single simulated client.
+#[derive(Default)]
+struct ClientStats {
+    sent: BTreeMap<RequestKind, u64>,
+    latencies: BTreeMap<RequestKind, Vec<Duration>>,
+    errors: BTreeMap<String, u64>,
+}
+
+impl ClientStats {
+    /// Counts a failure with the given reason.
+    fn error(&mut self, reason: &str) {
+        *self.errors.entry(reason.to_string()).or_default() += 1;
+    }
+}
+
+/// Runs the load test and returns its results once every client finished.
+pub async fn run(config: LoadConfig) -> Result<Report> {
+    if config.clients == 0 || config.rate <= 0.0 {
+        bail!("The load needs at least one client and a positive rate");
+    }
+    info!(
+        "Starting {} clients sending {} requests per second for {:?}",
+        config.clients, config.rate, config.duration
+    );
+    let config = Arc::new(config);
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..config.clients)
+        .map(|index| tokio::spawn(simulate(index, config.clone())))
+        .collect();
+    let mut stats = Vec::new();
+    for task in tasks {
+        stats.push(task.await.context("Simulated client panicked")?);
+    }
+    Ok(report(config.clients, start.elapsed(), stats))
+}
+
+/// Connects a simulated client and sends its share of the requests.
+async fn simulate(index: usize, config: Arc<LoadConfig>) -> ClientStats {
+    let mut stats = ClientStats::default();
+    // Unique names, so that no client receives what a previous run left in the mailboxes
+    let name = format!("loadgen-{}-{index}", std::process::id());
+    let mut stream = match create_client(config.ip, config.port).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Client {name} failed to connect: {e:#}");
+            stats.error("connect");
+            return stats;
+        }
+    };
+    let wire = match handshake(&mut stream, &name, config.codec).await {
+        Ok(welcome) => WireFormat::from(&welcome),
+        Err(e) => {
+            warn!("Client {name} failed the handshake: {e:#}");
+            stats.error("handshake");
+            return stats;
+        }
+    };
+    let (reader, mut writer) = stream.into_split();
+    let (tx, mut incoming) = mpsc::channel(32);
+    tokio::spawn(receive_loop(reader, wire, tx));
+
+    // Every client sends its share of the rate, starting at a different time
+    let period = Duration::from_secs_f64(config.clients as f64 / config.rate);
+    let offset = period.mul_f64(index as f64 / config.clients as f64);
+    let mut ticks = time::interval_at(Instant::now() + offset, period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let stop = Instant::now() + config.duration;
+    let drained = stop + DRAIN_TIMEOUT;
+    let mut kinds = config.mix.kinds();
+    // The server answers the requests of a connection in order
+    let mut pending: VecDeque<(RequestKind, Instant)> = VecDeque::new();
+
+    loop {
+        let sending = Instant::now() < stop;
+        if !sending && pending.is_empty() {
+            break;
+        }
+        tokio::select! {
+            _ = ticks.tick(), if sending => {
+                let kind = kinds.next().unwrap();
+                let request = match kind {
+                    RequestKind::Text => format!("load from {name}"),
+                    RequestKind::File => format!(".file {}", config.file.display()),
+                    RequestKind::Image => format!(".image {}", config.image.display()),
+                };
+                let envelope = Envelope::new(&name, MessageType::Text(request));
+                if envelope.send(&mut writer, wire).await.is_err() {
+                    stats.error("send");
+                    break;
+                }
+                *stats.sent.entry(kind).or_default() += 1;
+                pending.push_back((kind, Instant::now()));
+            }
+            received = incoming.recv() => {
+                let envelope = match received {
+                    Some(Ok(envelope)) => envelope,
+                    Some(Err(_)) | None => {
+                        stats.error("receive");
+                        break;
+                    }
+                };
+                // Acknowledge like a real client, so the server doesn't keep the messages
+                if envelope.needs_ack() {
+                    let ack = envelope.ack(&name, AckState::Delivered);
+                    if ack.send(&mut writer, wire).await.is_err() {
+                        stats.error("send");
+                        break;
+                    }
+                }
+                // Messages relayed from the other clients and confirmations aren't responses
+                if envelope.sender != SERVER_SENDER
+                    || matches!(envelope.message, MessageType::Accepted { .. })
+                {
+                    continue;
+                }
+                let Some((kind, sent_at)) = pending.pop_front() else {
+                    stats.error("unexpected response");
+                    continue;
+                };
+                match (kind, &envelope.message) {
+                    (RequestKind::Text, MessageType::Text(_))
+                    | (RequestKind::File, MessageType::File { .. })
+                    | (RequestKind::File, MessageType::FilePart { .. })
+                    | (RequestKind::Image, MessageType::Image { .. }) => {
+                        stats.latencies.entry(kind).or_default().push(sent_at.elapsed());
+                    }
+                    // Failures of .file and .image are answered with a text
+                    _ => stats.error(&format!("{kind} failed")),
+                }
+            }
+            _ = time::sleep_until(drained) => break,
+        }
+    }
+    if !pending.is_empty() {
+        *stats.errors.entry(String::from("timeout")).or_default() += pending.len() as u64;
+    }
+    stats
+}
+
+/// Merges the results of the clients.
+fn report(clients: usize, elapsed: Duration, stats: Vec<ClientStats>) -> Report {
+    let mut sent: BTreeMap<RequestKind, u64> = BTreeMap::new();
+    let mut latencies: BTreeMap<RequestKind, Vec<Duration>> = BTreeMap::new();
+    let mut errors: BTreeMap<String, u64> = BTreeMap::new();
+    for client in stats {
+        for (kind, count) in client.sent {
+            *sent.entry(kind).or_default() += count;
+        }
+        for (kind, samples) in client.latencies {
+            latencies.entry(kind).or_default().extend(samples);
+        }
+        for (reason, count) in client.errors {
+            *errors.entry(reason).or_default() += count;
+        }
+    }
+
+    let mut all: Vec<Duration> = latencies.values().flatten().copied().collect();
+    all.sort_unstable();
+    let kinds = sent
+        .iter()
+        .map(|(&kind, &sent)| {
+            let mut samples = latencies.remove(&kind).unwrap_or_default();
+            samples.sort_unstable();
+            KindReport {
+                kind,
+                sent,
+                completed: samples.len() as u64,
+                latency: Latency::of(&samples),
+            }
+        })
+        .collect();
+    Report {
+        clients,
+        elapsed_secs: elapsed.as_secs_f64(),
+        sent: sent.values().sum(),
+        completed: all.len() as u64,
+        throughput: all.len() as f64 / elapsed.as_secs_f64(),
+        latency: Latency::of(&all),
+        kinds,
+        errors,
+    }
+}
+
+impl Latency {
+    /// Computes the percentiles of sorted samples.
+    fn of(sorted: &[Duration]) -> Self {
+        Latency {
+            p50_ms: percentile(sorted, 50.0),
+            p95_ms: percentile(sorted, 95.0),
+            p99_ms: percentile(sorted, 99.0),
+        }
+    }
+}
+
+/// Returns the nearest-rank percentile of sorted samples in milliseconds.
+fn percentile(sorted: &[Duration], percent: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1].as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} clients, {:.1} s, {:.1} requests/s",
+            self.clients, self.elapsed_secs, self.throughput
+        )?;
+        writeln!(
+            f,
+            "{:<8}{:>10}{:>11}{:>10}{:>10}{:>10}",
+            "kind", "sent", "completed", "p50 ms", "p95 ms", "p99 ms"
+        )?;
+        writeln!(f, "{:-<59}", "")?;
+        let total = (
+            String::from("total"),
+            self.sent,
+            self.completed,
+            self.latency,
+        );
+        let rows = self
+            .kinds
+            .iter()
+            .map(|kind| {
+                (
+                    kind.kind.to_string(),
+                    kind.sent,
+                    kind.completed,
+                    kind.latency,
+                )
+            })
+            .chain(std::iter::once(total));
+        for (kind, sent, completed, latency) in rows {
+            writeln!(
+                f,
+                "{kind:<8}{sent:>10}{completed:>11}{:>10.2}{:>10.2}{:>10.2}",
+                latency.p50_ms, latency.p95_ms, latency.p99_ms
+            )?;
+        }
+        if self.errors.is_empty() {
+            return writeln!(f, "No errors");
+        }
+        writeln!(f, "Errors:")?;
+        for (reason, count) in &self.errors {
+            writeln!(f, "  {reason}: {count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_spreads_kinds() {
+        let mix: Mix = "text=2,file=1,image=0".parse().unwrap();
+        let kinds: Vec<RequestKind> = mix.kinds().take(6).collect();
+        let (text, file) = (RequestKind::Text, RequestKind::File);
+        assert_eq!(kinds, vec![text, file, text, text, file, text]);
+        assert!("text=0".parse::<Mix>().is_err());
+        assert!("video=1".parse::<Mix>().is_err());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let samples: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        let latency = Latency::of(&samples);
+        assert_eq!(latency.p50_ms, 50.0);
+        assert_eq!(latency.p95_ms, 95.0);
+        assert_eq!(latency.p99_ms, 99.0);
+        assert_eq!(Latency::of(&[]), Latency::default());
+    }
+}
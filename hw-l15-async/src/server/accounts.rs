@@ -0,0 +1,330 @@
+use crate::common::crypto::{password_hash, verify_password};
+use crate::common::envelope::SERVER_SENDER;
+use crate::common::LibError;
+use log::info;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Shortest password an account accepts.
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Reasons an account can't be created, changed or logged into.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AccountError {
+    #[error("User name can't be empty")]
+    EmptyName,
+    #[error("User name {0} is reserved")]
+    ReservedName(String),
+    #[error("User {0} already has an account")]
+    Taken(String),
+    #[error("User {0} has no account")]
+    Unknown(String),
+    #[error("Password has to have at least {MIN_PASSWORD_LEN} characters")]
+    ShortPassword,
+    #[error("Password can't contain spaces, commands take it as one word")]
+    SpaceInPassword,
+    #[error("{0} has an account, it has to log in with its password")]
+    PasswordRequired(String),
+    #[error("Wrong user name or password")]
+    WrongPassword,
+    #[error("Account of {0} is disabled")]
+    Disabled(String),
+    #[error("Password couldn't be hashed: {0}")]
+    Hashing(String),
+}
+
+impl From<LibError> for AccountError {
+    fn from(e: LibError) -> Self {
+        AccountError::Hashing(e.to_string())
+    }
+}
+
+/// Account of a user who logs in with a password, only the Argon2 hash of it is kept.
+#[derive(Debug, Clone)]
+struct Account {
+    hash: String,
+    admin: bool,
+    disabled: bool,
+}
+
+/// Password hash of an account, copied out of the accounts so that the slow Argon2 check doesn't
+/// hold their lock.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    name: String,
+    hash: String,
+    disabled: bool,
+}
+
+impl Credentials {
+    /// Checks the password the user logged in with, run it through `hashing`.
+    pub fn verify(&self, password: Option<&str>) -> Result<(), AccountError> {
+        let password = password.ok_or_else(|| AccountError::PasswordRequired(self.name.clone()))?;
+        if !verify_password(password, &self.hash) {
+            return Err(AccountError::WrongPassword);
+        }
+        if self.disabled {
+            return Err(AccountError::Disabled(self.name.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Summary of an account, without its password hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountInfo {
+    pub name: String,
+    pub admin: bool,
+    pub disabled: bool,
+}
+
+/// Users who log in with a password, names without an account stay open to anyone.
+#[derive(Default)]
+pub struct Accounts {
+    accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+    /// Creates an account, admins may manage the accounts of the others.
+    pub fn add(&mut self, name: &str, password: &str, admin: bool) -> Result<(), AccountError> {
+        self.check_name(name)?;
+        self.insert(name, hash_password(password)?, admin)
+    }
+
+    /// Creates an account with the password hashed by `hash_password`.
+    pub fn insert(&mut self, name: &str, hash: String, admin: bool) -> Result<(), AccountError> {
+        self.check_name(name)?;
+        let account = Account {
+            hash,
+            admin,
+            disabled: false,
+        };
+        self.accounts.insert(name.to_string(), account);
+        info!(target: "audit", "Added the account of {name}, admin: {admin}");
+        Ok(())
+    }
+
+    /// Removes the account, its sessions end with their next message and the name is open again.
+    pub fn remove(&mut self, name: &str) -> Result<(), AccountError> {
+        self.accounts
+            .remove(name)
+            .ok_or_else(|| AccountError::Unknown(name.to_string()))?;
+        info!(target: "audit", "Removed the account of {name}");
+        Ok(())
+    }
+
+    /// Disables the account, its sessions end with their next message and nobody can log in.
+    pub fn disable(&mut self, name: &str) -> Result<(), AccountError> {
+        let account = self
+            .accounts
+            .get_mut(name)
+            .ok_or_else(|| AccountError::Unknown(name.to_string()))?;
+        account.disabled = true;
+        info!(target: "audit", "Disabled the account of {name}");
+        Ok(())
+    }
+
+    /// Replaces the password of the user, who has to know the current one.
+    pub fn change_password(
+        &mut self,
+        name: &str,
+        current: &str,
+        new: &str,
+    ) -> Result<(), AccountError> {
+        let credentials = self
+            .credentials(name)
+            .ok_or_else(|| AccountError::Unknown(name.to_string()))?;
+        credentials.verify(Some(current))?;
+        self.replace_hash(&credentials, hash_password(new)?)
+    }
+
+    /// Replaces the password hash the current password was verified against with a new one.
+    ///
+    /// Fails if the password was changed in the meantime.
+    pub fn replace_hash(
+        &mut self,
+        credentials: &Credentials,
+        hash: String,
+    ) -> Result<(), AccountError> {
+        let name = &credentials.name;
+        match self.accounts.get_mut(name) {
+            Some(account) if account.hash == credentials.hash => account.hash = hash,
+            Some(_) => return Err(AccountError::WrongPassword),
+            None => return Err(AccountError::Unknown(name.clone())),
+        }
+        info!(target: "audit", "{name} changed their password");
+        Ok(())
+    }
+
+    /// Returns a copy of the password hash of the user, None if the name has no account.
+    pub fn credentials(&self, name: &str) -> Option<Credentials> {
+        self.accounts.get(name).map(|account| Credentials {
+            name: name.to_string(),
+            hash: account.hash.clone(),
+            disabled: account.disabled,
+        })
+    }
+
+    /// Checks the password a client logged in with, returns whether the name has an account.
+    ///
+    /// Names without an account are open, the password is ignored for them.
+    pub fn authenticate(&self, name: &str, password: Option<&str>) -> Result<bool, AccountError> {
+        match self.credentials(name) {
+            Some(credentials) => credentials.verify(password).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns whether a session that logged in, or didn't, may go on under the name.
+    ///
+    /// Sessions of removed or disabled accounts end, and so do the ones that took the name
+    /// before an account was created for it.
+    pub fn allows(&self, name: &str, logged_in: bool) -> bool {
+        match self.accounts.get(name) {
+            Some(account) => logged_in && !account.disabled,
+            None => !logged_in,
+        }
+    }
+
+    /// Returns whether the user has an enabled admin account.
+    pub fn is_admin(&self, name: &str) -> bool {
+        self.accounts
+            .get(name)
+            .is_some_and(|account| account.admin && !account.disabled)
+    }
+
+    /// Checks that an account can be created for the name.
+    fn check_name(&self, name: &str) -> Result<(), AccountError> {
+        if name.trim().is_empty() {
+            return Err(AccountError::EmptyName);
+        }
+        if name == SERVER_SENDER {
+            return Err(AccountError::ReservedName(name.to_string()));
+        }
+        if self.accounts.contains_key(name) {
+            return Err(AccountError::Taken(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Returns the accounts sorted by name.
+    pub fn list(&self) -> Vec<AccountInfo> {
+        let mut accounts: Vec<AccountInfo> = self
+            .accounts
+            .iter()
+            .map(|(name, account)| AccountInfo {
+                name: name.clone(),
+                admin: account.admin,
+                disabled: account.disabled,
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        accounts
+    }
+}
+
+/// Runs the slow Argon2 hashing or check of a password on the blocking threads, so that it holds
+/// neither an async worker nor the lock of the accounts.
+pub async fn hashing<T>(
+    work: impl FnOnce() -> Result<T, AccountError> + Send + 'static,
+) -> Result<T, AccountError>
+where
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| AccountError::Hashing(e.to_string()))?
+}
+
+/// Hashes a password that can be accepted.
+pub fn hash_password(password: &str) -> Result<String, AccountError> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(AccountError::ShortPassword);
+    }
+    if password.contains(char::is_whitespace) {
+        return Err(AccountError::SpaceInPassword);
+    }
+    Ok(password_hash(password)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_with_password() {
+        let mut accounts = Accounts::default();
+        accounts.add("alice", "correct-horse", false).unwrap();
+        // Only the hash of the password is kept
+        assert!(!accounts.accounts["alice"].hash.contains("correct-horse"));
+
+        assert_eq!(
+            accounts.authenticate("alice", Some("correct-horse")),
+            Ok(true)
+        );
+        assert_eq!(accounts.authenticate("bob", None), Ok(false));
+        assert_eq!(
+            accounts.authenticate("alice", None),
+            Err(AccountError::PasswordRequired(String::from("alice")))
+        );
+        assert_eq!(
+            accounts.authenticate("alice", Some("battery-staple")),
+            Err(AccountError::WrongPassword)
+        );
+        assert_eq!(
+            accounts.change_password("alice", "correct-horse", "short"),
+            Err(AccountError::ShortPassword)
+        );
+        accounts
+            .change_password("alice", "correct-horse", "battery-staple")
+            .unwrap();
+        assert_eq!(
+            accounts.authenticate("alice", Some("battery-staple")),
+            Ok(true)
+        );
+        assert_eq!(
+            accounts.add("bob", "correct horse", false),
+            Err(AccountError::SpaceInPassword)
+        );
+        assert_eq!(
+            accounts.add("alice", "correct-horse", true),
+            Err(AccountError::Taken(String::from("alice")))
+        );
+
+        // A change made while the copied hash was checked isn't overwritten
+        let stale = accounts.credentials("alice").unwrap();
+        accounts
+            .change_password("alice", "battery-staple", "correct-horse")
+            .unwrap();
+        let hash = hash_password("another-one").unwrap();
+        assert_eq!(
+            accounts.replace_hash(&stale, hash),
+            Err(AccountError::WrongPassword)
+        );
+    }
+
+    #[test]
+    fn test_disabled_and_removed_accounts_end_sessions() {
+        let mut accounts = Accounts::default();
+        accounts.add("root", "correct-horse", true).unwrap();
+        accounts.add("alice", "correct-horse", false).unwrap();
+        assert!(accounts.is_admin("root") && !accounts.is_admin("alice"));
+        // A session that took the name before the account was created ends too
+        assert!(accounts.allows("alice", true) && !accounts.allows("alice", false));
+        assert!(accounts.allows("bob", false));
+
+        accounts.disable("alice").unwrap();
+        assert!(!accounts.allows("alice", true));
+        assert_eq!(
+            accounts.authenticate("alice", Some("correct-horse")),
+            Err(AccountError::Disabled(String::from("alice")))
+        );
+        accounts.remove("alice").unwrap();
+        assert!(!accounts.allows("alice", true) && accounts.allows("alice", false));
+        assert_eq!(
+            accounts.remove("alice"),
+            Err(AccountError::Unknown(String::from("alice")))
+        );
+        assert_eq!(accounts.list().len(), 1);
+    }
+}
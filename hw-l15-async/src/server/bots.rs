@@ -0,0 +1,176 @@
+use crate::common::crypto::{random_token, token_hash as hash};
+use crate::common::envelope::SERVER_SENDER;
+use log::info;
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// Prefix of the issued tokens, so that they're easy to recognize in configs and logs.
+const TOKEN_PREFIX: &str = "bot_";
+
+/// Reasons a token can't be issued, revoked or used.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BotError {
+    #[error("Bot name can't be empty")]
+    EmptyName,
+    #[error("Bot name {0} is reserved")]
+    ReservedName(String),
+    #[error("Bot {0} needs at least one room")]
+    NoRooms(String),
+    #[error("Invalid or revoked bot token")]
+    InvalidToken,
+    #[error("Token was issued to bot {0}")]
+    WrongName(String),
+    #[error("{0} is a bot, it has to connect with its token")]
+    TokenRequired(String),
+    #[error("Unknown bot token {0}")]
+    UnknownId(u64),
+}
+
+/// What a bot token allows its holder to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotGrant {
+    /// Identifies the token when listing or revoking it.
+    pub id: u64,
+    pub name: String,
+    /// Rooms the bot may post to, the first one is where it starts unless it may use the default.
+    pub rooms: Vec<String>,
+}
+
+impl BotGrant {
+    /// Returns whether the bot may join the room.
+    pub fn allows(&self, room: &str) -> bool {
+        self.rooms.iter().any(|allowed| allowed == room)
+    }
+}
+
+/// API tokens of the bots, only their SHA-256 hashes are kept.
+#[derive(Default)]
+pub struct Bots {
+    tokens: HashMap<[u8; 32], BotGrant>,
+    next_id: u64,
+}
+
+impl Bots {
+    /// Issues a token for the bot scoped to the rooms, returns its ID and the token itself.
+    ///
+    /// The token is shown only this once, the server can't tell it again.
+    pub fn issue(&mut self, name: &str, rooms: &[String]) -> Result<(u64, String), BotError> {
+        if name.trim().is_empty() {
+            return Err(BotError::EmptyName);
+        }
+        if name == SERVER_SENDER {
+            return Err(BotError::ReservedName(name.to_string()));
+        }
+        if rooms.is_empty() {
+            return Err(BotError::NoRooms(name.to_string()));
+        }
+        let token = random_token(TOKEN_PREFIX);
+        self.next_id += 1;
+        let grant = BotGrant {
+            id: self.next_id,
+            name: name.to_string(),
+            rooms: rooms.to_vec(),
+        };
+        info!("Issued token {} for bot {name} in {rooms:?}", grant.id);
+        self.tokens.insert(hash(&token), grant);
+        Ok((self.next_id, token))
+    }
+
+    /// Revokes the token, the bot is disconnected with its next message.
+    pub fn revoke(&mut self, id: u64) -> Result<BotGrant, BotError> {
+        let hash = self
+            .tokens
+            .iter()
+            .find_map(|(hash, grant)| (grant.id == id).then_some(*hash))
+            .ok_or(BotError::UnknownId(id))?;
+        let grant = self.tokens.remove(&hash).ok_or(BotError::UnknownId(id))?;
+        info!("Revoked token {id} of bot {}", grant.name);
+        Ok(grant)
+    }
+
+    /// Checks the name and token a client introduced itself with, returns the grant of a bot.
+    ///
+    /// Names of the bots are reserved, nobody can connect as a bot without its token.
+    pub fn authenticate(
+        &self,
+        name: &str,
+        token: Option<&str>,
+    ) -> Result<Option<BotGrant>, BotError> {
+        let Some(token) = token else {
+            return match self.tokens.values().any(|grant| grant.name == name) {
+                true => Err(BotError::TokenRequired(name.to_string())),
+                false => Ok(None),
+            };
+        };
+        let grant = self
+            .tokens
+            .get(&hash(token))
+            .ok_or(BotError::InvalidToken)?;
+        if grant.name != name {
+            return Err(BotError::WrongName(grant.name.clone()));
+        }
+        Ok(Some(grant.clone()))
+    }
+
+    /// Returns whether the token wasn't revoked.
+    pub fn is_active(&self, id: u64) -> bool {
+        self.tokens.values().any(|grant| grant.id == id)
+    }
+
+    /// Returns the grants of the active tokens, oldest first.
+    pub fn list(&self) -> Vec<BotGrant> {
+        let mut grants: Vec<BotGrant> = self.tokens.values().cloned().collect();
+        grants.sort_by_key(|grant| grant.id);
+        grants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rooms(rooms: &[&str]) -> Vec<String> {
+        rooms.iter().map(|room| room.to_string()).collect()
+    }
+
+    #[test]
+    fn test_authenticate_issued_token() {
+        let mut bots = Bots::default();
+        let (id, token) = bots.issue("ci", &rooms(&["builds"])).unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        // Only the hash of the token is kept
+        assert!(bots.tokens.contains_key(&hash(&token)));
+
+        let grant = bots.authenticate("ci", Some(&token)).unwrap().unwrap();
+        assert_eq!(grant.id, id);
+        assert!(grant.allows("builds") && !grant.allows("lobby"));
+        assert_eq!(bots.authenticate("alice", None), Ok(None));
+        assert_eq!(
+            bots.authenticate("ci", None),
+            Err(BotError::TokenRequired(String::from("ci")))
+        );
+        assert_eq!(
+            bots.authenticate("alice", Some(&token)),
+            Err(BotError::WrongName(String::from("ci")))
+        );
+        assert_eq!(
+            bots.issue("ci", &[]),
+            Err(BotError::NoRooms(String::from("ci")))
+        );
+    }
+
+    #[test]
+    fn test_revoked_token_is_rejected() {
+        let mut bots = Bots::default();
+        let (id, token) = bots.issue("ci", &rooms(&["lobby"])).unwrap();
+        let (other, _) = bots.issue("ci", &rooms(&["lobby"])).unwrap();
+        assert_eq!(bots.revoke(id).unwrap().name, "ci");
+        assert!(!bots.is_active(id) && bots.is_active(other));
+        assert_eq!(
+            bots.authenticate("ci", Some(&token)),
+            Err(BotError::InvalidToken)
+        );
+        assert_eq!(bots.revoke(id), Err(BotError::UnknownId(id)));
+        assert_eq!(bots.list().len(), 1);
+    }
+}
@@ -0,0 +1,432 @@
+use crate::server::commands::CommandRegistry;
+use crate::server::hub::Hub;
+use crate::server::mailbox::{MailboxConfig, Mailboxes};
+use crate::server::resume::DEFAULT_SESSION_TTL;
+use crate::server::{create_server, server_loop, ws};
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Addresses and limits of a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerConfig {
+    pub ip: Ipv4Addr,
+    /// TCP port of the native clients, 0 picks a free one.
+    pub port: u16,
+    /// Port of the WebSocket gateway, defaults to the TCP port + 1, or to a free one with port 0.
+    pub ws_port: Option<u16>,
+    /// Path of a Unix socket the native clients can connect to as well.
+    pub unix: Option<PathBuf>,
+    /// Permissions of the Unix socket, only users allowed to write to it can connect.
+    pub unix_mode: u32,
+    pub mailbox: MailboxConfig,
+    /// Directory the mailboxes are kept in across restarts, in memory only without one.
+    pub data_dir: Option<PathBuf>,
+    /// Time a disconnected client has to resume its session.
+    pub session_ttl: Duration,
+    /// Directory the clients can browse and download from.
+    pub served_dir: PathBuf,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            ip: Ipv4Addr::LOCALHOST,
+            port: 11111,
+            ws_port: None,
+            unix: None,
+            unix_mode: 0o660,
+            mailbox: MailboxConfig::default(),
+            data_dir: None,
+            session_ttl: DEFAULT_SESSION_TTL,
+            served_dir: PathBuf::from("."),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Returns the port of the WebSocket gateway, the one following the TCP port unless it's set.
+    pub fn gateway_port(&self) -> Result<u16> {
+        match (self.ws_port, self.port) {
+            (Some(ws_port), _) => Ok(ws_port),
+            (None, 0) => Ok(0),
+            (None, port) => port.checked_add(1).with_context(|| {
+                format!("TCP port {port} has no following WebSocket port, set one explicitly")
+            }),
+        }
+    }
+}
+
+/// Chat server serving the native clients and the WebSocket gateway from the same rooms.
+pub struct Server;
+
+impl Server {
+    /// Starts configuring a server.
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+}
+
+/// Configures and starts a server.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: ServerConfig,
+    commands: Option<CommandRegistry>,
+    storage: Option<Arc<Hub>>,
+}
+
+impl ServerBuilder {
+    /// Replaces the whole configuration.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the IP address to bind to.
+    pub fn ip(mut self, ip: Ipv4Addr) -> Self {
+        self.config.ip = ip;
+        self
+    }
+
+    /// Sets the TCP port, 0 picks a free one.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Sets the WebSocket port, 0 picks a free one.
+    pub fn ws_port(mut self, port: u16) -> Self {
+        self.config.ws_port = Some(port);
+        self
+    }
+
+    /// Listens on a Unix socket as well, with the permissions set by `unix_mode`.
+    pub fn unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.unix = Some(path.into());
+        self
+    }
+
+    /// Sets the permissions of the Unix socket, e.g. `0o600` for the owner only.
+    pub fn unix_mode(mut self, mode: u32) -> Self {
+        self.config.unix_mode = mode;
+        self
+    }
+
+    /// Sets the limits of the mailboxes of the offline users.
+    pub fn mailbox(mut self, mailbox: MailboxConfig) -> Self {
+        self.config.mailbox = mailbox;
+        self
+    }
+
+    /// Keeps the mailboxes in the directory, so that they survive a restart.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the time a disconnected client has to resume its session.
+    pub fn session_ttl(mut self, ttl: Duration) -> Self {
+        self.config.session_ttl = ttl;
+        self
+    }
+
+    /// Sets the directory the clients can browse and download from, the working one by default.
+    pub fn served_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.served_dir = dir.into();
+        self
+    }
+
+    /// Sets the commands the clients can run, the built-in ones by default.
+    pub fn commands(mut self, commands: CommandRegistry) -> Self {
+        self.commands = Some(commands);
+        self
+    }
+
+    /// Keeps the rooms, mailboxes, history and keys in the given hub, e.g. one of an old server.
+    ///
+    /// The hub brings its own mailbox limits, data directory, session TTL, served directory and
+    /// commands, starting fails if any of them is set on the builder as well.
+    pub fn storage(mut self, hub: Arc<Hub>) -> Self {
+        self.storage = Some(hub);
+        self
+    }
+
+    /// Returns the settings of the hub changed from their defaults.
+    fn hub_settings(&self) -> Vec<&'static str> {
+        let defaults = ServerConfig::default();
+        let changed = [
+            ("mailbox", self.config.mailbox != defaults.mailbox),
+            ("data_dir", self.config.data_dir.is_some()),
+            (
+                "session_ttl",
+                self.config.session_ttl != defaults.session_ttl,
+            ),
+            ("served_dir", self.config.served_dir != defaults.served_dir),
+            ("commands", self.commands.is_some()),
+        ];
+        changed
+            .into_iter()
+            .filter_map(|(setting, changed)| changed.then_some(setting))
+            .collect()
+    }
+
+    /// Checks the settings, binds the listeners and starts serving in the background.
+    pub async fn start(self) -> Result<ServerHandle> {
+        let ws_port = self.config.gateway_port()?;
+        let settings = self.hub_settings();
+        if self.storage.is_some() && !settings.is_empty() {
+            bail!(
+                "The hub passed to storage() brings its own settings, {} can't be set as well",
+                settings.join(", ")
+            );
+        }
+        let ServerConfig {
+            ip,
+            port,
+            unix,
+            unix_mode,
+            mailbox,
+            data_dir,
+            session_ttl,
+            served_dir,
+            ..
+        } = self.config;
+        // Both transports share the same rooms, the hub is ready before anything is bound
+        let hub = match self.storage {
+            Some(hub) => hub,
+            None => {
+                let served = tokio::fs::metadata(&served_dir)
+                    .await
+                    .with_context(|| format!("Failed to serve {}", served_dir.display()))?;
+                if !served.is_dir() {
+                    bail!("Served path {} is not a directory", served_dir.display());
+                }
+                let mut hub = Hub::new(mailbox, self.commands.unwrap_or_default());
+                if let Some(dir) = &data_dir {
+                    let mailboxes = Mailboxes::open(mailbox, dir).with_context(|| {
+                        format!("Failed to load the mailboxes from {}", dir.display())
+                    })?;
+                    hub = hub.with_mailboxes(mailboxes);
+                }
+                Arc::new(
+                    hub.with_session_ttl(session_ttl)
+                        .with_served_dir(served_dir),
+                )
+            }
+        };
+        // Create the server listeners
+        let server = create_server(ip, port)
+            .await
+            .context("Failed to create server")?;
+        let ws_server = create_server(ip, ws_port)
+            .await
+            .context("Failed to create WebSocket gateway")?;
+        let addr = server
+            .local_addr()
+            .context("Failed to get server address")?;
+        let ws_addr = ws_server
+            .local_addr()
+            .context("Failed to get WebSocket gateway address")?;
+        let unix_server = match &unix {
+            Some(path) => Some(
+                bind_unix(path, unix_mode)
+                    .await
+                    .context("Failed to create Unix socket")?,
+            ),
+            None => None,
+        };
+
+        let (shutdown, stopped) = watch::channel(false);
+        let (tcp_hub, ws_hub, ws_stopped) = (hub.clone(), hub.clone(), stopped.clone());
+        let (unix_hub, unix_stopped, unix_path) = (hub.clone(), stopped.clone(), unix.clone());
+        // Start the server loops to handle incoming connections
+        let task = tokio::spawn(async move {
+            let served = tokio::try_join!(
+                async {
+                    server_loop(server, tcp_hub, stopped)
+                        .await
+                        .context("Server loop crashed")
+                },
+                async {
+                    ws::ws_loop(ws_server, ws_hub, ws_stopped)
+                        .await
+                        .context("WebSocket loop crashed")
+                },
+                async {
+                    match unix_server {
+                        Some(listener) => serve_unix(listener, unix_hub, unix_stopped).await,
+                        None => Ok(()),
+                    }
+                },
+            );
+            // Nobody can connect to the socket anymore
+            if let Some(path) = unix_path {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    warn!("Failed to remove Unix socket {}: {e}", path.display());
+                }
+            }
+            served?;
+            info!("Server shut down");
+            Ok(())
+        });
+        Ok(ServerHandle {
+            addr,
+            ws_addr,
+            unix,
+            hub,
+            shutdown,
+            task,
+        })
+    }
+}
+
+/// Running server, shut down when the handle is dropped.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    ws_addr: SocketAddr,
+    unix: Option<PathBuf>,
+    hub: Arc<Hub>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// Returns the address the native clients connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the address of the WebSocket gateway.
+    pub fn ws_addr(&self) -> SocketAddr {
+        self.ws_addr
+    }
+
+    /// Returns the path of the Unix socket, if the server listens on one.
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.unix.as_deref()
+    }
+
+    /// Returns the hub keeping the rooms and messages of the server.
+    pub fn hub(&self) -> &Arc<Hub> {
+        &self.hub
+    }
+
+    /// Stops accepting connections and asks the connected clients to quit.
+    pub fn shutdown(&self) {
+        info!("Shutting down the server");
+        self.shutdown.send_replace(true);
+    }
+
+    /// Waits until the server shuts down and every connection is closed.
+    pub async fn join(self) -> Result<()> {
+        let ServerHandle { shutdown, task, .. } = self;
+        let result = task.await.context("Server task panicked")?;
+        drop(shutdown);
+        result
+    }
+}
+
+/// Resolves once the server is shutting down, or its handle was dropped.
+pub(crate) async fn stopped(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|&stop| stop).await;
+}
+
+#[cfg(unix)]
+type UnixServer = tokio::net::UnixListener;
+#[cfg(not(unix))]
+type UnixServer = std::convert::Infallible;
+
+/// Binds the Unix socket, replacing the one a crashed server left behind.
+#[cfg(unix)]
+async fn bind_unix(path: &Path, mode: u32) -> Result<UnixServer> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = tokio::fs::symlink_metadata(path).await {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and isn't a socket", path.display());
+        }
+        tokio::fs::remove_file(path)
+            .await
+            .context("Failed to remove the previous socket")?;
+    }
+    let listener = UnixServer::bind(path).context("Failed to bind Unix socket")?;
+    // The permissions of the socket decide who can connect
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .await
+        .context("Failed to set socket permissions")?;
+    info!("Listener binded to {} with mode {mode:o}", path.display());
+    Ok(listener)
+}
+
+/// Fails, Unix sockets are available on Unix only.
+#[cfg(not(unix))]
+async fn bind_unix(_: &Path, _: u32) -> Result<UnixServer> {
+    anyhow::bail!("Unix sockets aren't supported on this platform")
+}
+
+/// Serves the native clients connecting over the Unix socket.
+#[cfg(unix)]
+async fn serve_unix(
+    listener: UnixServer,
+    hub: Arc<Hub>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    server_loop(listener, hub, shutdown)
+        .await
+        .context("Unix socket loop crashed")
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(listener: UnixServer, _: Arc<Hub>, _: watch::Receiver<bool>) -> Result<()> {
+    match listener {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gateway_port_follows_tcp_port() {
+        let config = ServerConfig::default();
+        assert_eq!(config.gateway_port().unwrap(), 11112);
+        let ephemeral = Server::builder().port(0).config;
+        assert_eq!(ephemeral.gateway_port().unwrap(), 0);
+        let last = Server::builder().port(u16::MAX).config;
+        assert!(last.gateway_port().is_err());
+        let explicit = Server::builder().port(u16::MAX).ws_port(8080).config;
+        assert_eq!(explicit.gateway_port().unwrap(), 8080);
+    }
+
+    #[tokio::test]
+    async fn test_settings_are_checked_before_binding() {
+        let hub = Arc::new(Hub::default());
+        let storage = Server::builder().port(0).storage(hub.clone());
+        let conflict = storage
+            .session_ttl(Duration::ZERO)
+            .start()
+            .await
+            .err()
+            .unwrap();
+        assert!(conflict.to_string().contains("session_ttl"));
+
+        // A missing directory is reported before binding fails on the taken port
+        let taken = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let missing = Server::builder().port(port).served_dir("no such dir");
+        let missing = missing.start().await.err().unwrap();
+        assert!(missing.to_string().starts_with("Failed to serve"));
+        let server = Server::builder()
+            .port(0)
+            .storage(hub)
+            .start()
+            .await
+            .unwrap();
+        server.shutdown();
+        server.join().await.unwrap();
+    }
+}
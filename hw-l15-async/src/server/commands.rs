@@ -0,0 +1,717 @@
+use crate::common::presence::Status;
+use crate::common::{MessageType, RefusalKind};
+use crate::server::hub::Session;
+use anyhow::Result;
+use async_trait::async_trait;
+use concurrency::{transform, AppType, Transformation};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Reason a command couldn't be carried out.
+#[derive(Error, Debug)]
+pub enum CommandError {
+    /// Answered with the usage of the command.
+    #[error("Missing argument")]
+    MissingArgument,
+    /// Answered with the message, e.g. a value that couldn't be parsed.
+    #[error("{0}")]
+    Invalid(String),
+    /// Answered with the message, the user isn't allowed to run the command.
+    #[error("{0}")]
+    NotPermitted(String),
+    /// Terminates the connection.
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+/// Arguments typed after the command name.
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a>(&'a str);
+
+impl<'a> Args<'a> {
+    /// Wraps the text following the command name.
+    pub fn new(args: &'a str) -> Self {
+        Args(args.trim())
+    }
+
+    /// Takes the next whitespace separated argument.
+    pub fn word(&mut self) -> Result<&'a str, CommandError> {
+        if self.0.is_empty() {
+            return Err(CommandError::MissingArgument);
+        }
+        let (arg, rest) = self
+            .0
+            .split_once(char::is_whitespace)
+            .unwrap_or((self.0, ""));
+        self.0 = rest.trim_start();
+        Ok(arg)
+    }
+
+    /// Takes the next argument and parses it.
+    pub fn parse<T>(&mut self) -> Result<T, CommandError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.word()?
+            .parse()
+            .map_err(|e: T::Err| CommandError::Invalid(e.to_string()))
+    }
+
+    /// Takes an optional `@<offset>` argument, 0 if it's missing.
+    pub fn offset(&mut self) -> Result<u64, CommandError> {
+        let Some(offset) = self.0.strip_prefix('@') else {
+            return Ok(0);
+        };
+        let (offset, rest) = offset
+            .split_once(char::is_whitespace)
+            .unwrap_or((offset, ""));
+        self.0 = rest.trim_start();
+        offset
+            .parse()
+            .map_err(|_| CommandError::Invalid(format!("Invalid offset @{offset}")))
+    }
+
+    /// Takes the rest of the line, e.g. a text containing spaces.
+    pub fn rest(self) -> Result<&'a str, CommandError> {
+        match self.0 {
+            "" => Err(CommandError::MissingArgument),
+            rest => Ok(rest),
+        }
+    }
+}
+
+/// Command the clients request with a text starting with a dot, e.g. `.join rust`.
+#[async_trait]
+pub trait CommandHandler: Send + Sync {
+    /// Returns the name typed after the dot.
+    fn name(&self) -> &str;
+
+    /// Returns the arguments shown in the help, e.g. `<room>`.
+    fn args(&self) -> &str {
+        ""
+    }
+
+    /// Returns the description shown in the help.
+    fn help(&self) -> &str;
+
+    /// Carries out the command for the session and returns the response.
+    async fn run(&self, session: &mut Session, args: Args<'_>)
+        -> Result<MessageType, CommandError>;
+}
+
+/// Commands available to the clients, by their name.
+pub struct CommandRegistry {
+    handlers: BTreeMap<String, Box<dyn CommandHandler>>,
+}
+
+impl Default for CommandRegistry {
+    /// Creates the registry with the built-in commands.
+    fn default() -> Self {
+        let mut commands = CommandRegistry::empty();
+        commands
+            .register(Help)
+            .register(Quit)
+            .register(File)
+            .register(Get)
+            .register(Ls)
+            .register(Stat)
+            .register(Image)
+            .register(Join)
+            .register(Key)
+            .register(Edit)
+            .register(Delete)
+            .register(SetStatus)
+            .register(Account)
+            .register(Passwd)
+            .register(User);
+        for transformation in Transformation::ALL {
+            commands.register(Transform(transformation));
+        }
+        commands
+    }
+}
+
+impl CommandRegistry {
+    /// Creates a registry without any command.
+    pub fn empty() -> Self {
+        CommandRegistry {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the command, replacing the one with the same name.
+    pub fn register(&mut self, handler: impl CommandHandler + 'static) -> &mut Self {
+        self.handlers
+            .insert(handler.name().to_string(), Box::new(handler));
+        self
+    }
+
+    /// Returns the usage and description of every command, sorted by name.
+    pub fn help(&self) -> String {
+        let usages: Vec<String> = self
+            .handlers
+            .values()
+            .map(|handler| usage(handler.as_ref()))
+            .collect();
+        let width = usages.iter().map(String::len).max().unwrap_or(0);
+        let lines: Vec<String> = usages
+            .iter()
+            .zip(self.handlers.values())
+            .map(|(usage, handler)| format!("{usage:width$}  {}", handler.help()))
+            .collect();
+        format!("Available commands:\n{}", lines.join("\n"))
+    }
+
+    /// Runs the command typed by the client, e.g. `.join rust`.
+    pub async fn run(&self, session: &mut Session, input: &str) -> Result<MessageType> {
+        let input = input.trim().trim_start_matches('.');
+        let (name, args) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        let Some(handler) = self.handlers.get(name) else {
+            return Ok(MessageType::refusal(
+                RefusalKind::UnknownCommand,
+                format!("Unknown command .{name}, use .help to list the commands"),
+            ));
+        };
+        // Wrong arguments are answered, only failures of the server end the connection
+        match handler.run(session, Args::new(args)).await {
+            Ok(response) => Ok(response),
+            Err(CommandError::MissingArgument) => Ok(MessageType::refusal(
+                RefusalKind::Usage,
+                format!("Usage: {}", usage(handler.as_ref())),
+            )),
+            Err(CommandError::Invalid(reason)) => {
+                Ok(MessageType::refusal(RefusalKind::InvalidArgument, reason))
+            }
+            Err(CommandError::NotPermitted(reason)) => {
+                Ok(MessageType::refusal(RefusalKind::NotPermitted, reason))
+            }
+            Err(CommandError::Failed(e)) => Err(e),
+        }
+    }
+}
+
+/// Returns how the command is typed, e.g. `.join <room>`.
+fn usage(handler: &dyn CommandHandler) -> String {
+    match handler.args() {
+        "" => format!(".{}", handler.name()),
+        args => format!(".{} {args}", handler.name()),
+    }
+}
+
+/// Lists the commands of the session's registry.
+struct Help;
+
+#[async_trait]
+impl CommandHandler for Help {
+    fn name(&self) -> &str {
+        "help"
+    }
+
+    fn help(&self) -> &str {
+        "lists the commands"
+    }
+
+    async fn run(&self, session: &mut Session, _: Args<'_>) -> Result<MessageType, CommandError> {
+        Ok(MessageType::Text(session.commands().help()))
+    }
+}
+
+/// Terminates the connection.
+struct Quit;
+
+#[async_trait]
+impl CommandHandler for Quit {
+    fn name(&self) -> &str {
+        "quit"
+    }
+
+    fn help(&self) -> &str {
+        "terminates the connection"
+    }
+
+    async fn run(&self, _: &mut Session, _: Args<'_>) -> Result<MessageType, CommandError> {
+        Ok(MessageType::Quit)
+    }
+}
+
+/// Sends a file of the server to the client.
+struct File;
+
+#[async_trait]
+impl CommandHandler for File {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn args(&self) -> &str {
+        "[@offset] <path>"
+    }
+
+    fn help(&self) -> &str {
+        "sends the file, from the byte offset on if given"
+    }
+
+    async fn run(&self, _: &mut Session, mut args: Args<'_>) -> Result<MessageType, CommandError> {
+        let offset = args.offset()?;
+        Ok(MessageType::from_file_range(Path::new(args.rest()?), offset).await)
+    }
+}
+
+/// Lists a directory the server serves.
+struct Ls;
+
+#[async_trait]
+impl CommandHandler for Ls {
+    fn name(&self) -> &str {
+        "ls"
+    }
+
+    fn args(&self) -> &str {
+        "[dir]"
+    }
+
+    fn help(&self) -> &str {
+        "lists the served directory with the sizes and modification times"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        session.served().list(args.rest().unwrap_or("/")).await
+    }
+}
+
+/// Returns the metadata of a served file.
+struct Stat;
+
+#[async_trait]
+impl CommandHandler for Stat {
+    fn name(&self) -> &str {
+        "stat"
+    }
+
+    fn args(&self) -> &str {
+        "<path>"
+    }
+
+    fn help(&self) -> &str {
+        "shows the kind, size and modification time of the served file"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        session.served().stat(args.rest()?).await
+    }
+}
+
+/// Sends a file of the served directory to the client.
+struct Get;
+
+#[async_trait]
+impl CommandHandler for Get {
+    fn name(&self) -> &str {
+        "get"
+    }
+
+    fn args(&self) -> &str {
+        "[@offset] <path>"
+    }
+
+    fn help(&self) -> &str {
+        "sends the file of the served directory, from the byte offset on if given"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let offset = args.offset()?;
+        session.served().get(args.rest()?, offset).await
+    }
+}
+
+/// Sends an image of the server to the client, as it is.
+struct Image;
+
+#[async_trait]
+impl CommandHandler for Image {
+    fn name(&self) -> &str {
+        "image"
+    }
+
+    fn args(&self) -> &str {
+        "<path>"
+    }
+
+    fn help(&self) -> &str {
+        "sends the PNG, JPEG, GIF or WebP image"
+    }
+
+    async fn run(&self, _: &mut Session, args: Args<'_>) -> Result<MessageType, CommandError> {
+        Ok(MessageType::from_image(Path::new(args.rest()?)).await)
+    }
+}
+
+/// Moves the session to another room.
+struct Join;
+
+#[async_trait]
+impl CommandHandler for Join {
+    fn name(&self) -> &str {
+        "join"
+    }
+
+    fn args(&self) -> &str {
+        "<room>"
+    }
+
+    fn help(&self) -> &str {
+        "moves you to the room, creating it if needed"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        session.switch_room(args.word()?)
+    }
+}
+
+/// Hands out the public key of a user.
+struct Key;
+
+#[async_trait]
+impl CommandHandler for Key {
+    fn name(&self) -> &str {
+        "key"
+    }
+
+    fn args(&self) -> &str {
+        "<user>"
+    }
+
+    fn help(&self) -> &str {
+        "returns the public key the user published"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let user = args.word()?;
+        match session.public_key(user) {
+            Some(key) => Ok(MessageType::PublicKey {
+                user: user.to_string(),
+                key,
+            }),
+            None => Err(CommandError::Invalid(format!(
+                "User {user} hasn't published a public key"
+            ))),
+        }
+    }
+}
+
+/// Changes a previously sent message.
+struct Edit;
+
+#[async_trait]
+impl CommandHandler for Edit {
+    fn name(&self) -> &str {
+        "edit"
+    }
+
+    fn args(&self) -> &str {
+        "<id> <new text>"
+    }
+
+    fn help(&self) -> &str {
+        "replaces the text of your message"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let id = args.word()?;
+        session.revise(id, Some(args.rest()?))
+    }
+}
+
+/// Deletes a previously sent message.
+struct Delete;
+
+#[async_trait]
+impl CommandHandler for Delete {
+    fn name(&self) -> &str {
+        "delete"
+    }
+
+    fn args(&self) -> &str {
+        "<id>"
+    }
+
+    fn help(&self) -> &str {
+        "deletes your message"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        session.revise(args.word()?, None)
+    }
+}
+
+/// Sets the presence shown to the room.
+struct SetStatus;
+
+#[async_trait]
+impl CommandHandler for SetStatus {
+    fn name(&self) -> &str {
+        "status"
+    }
+
+    fn args(&self) -> &str {
+        "<online|away|busy>"
+    }
+
+    fn help(&self) -> &str {
+        "sets the status shown to the room"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let status: Status = args.parse()?;
+        session.set_status(status);
+        Ok(MessageType::Text(format!("Status set to {status}")))
+    }
+}
+
+/// Manages the account of the session's user.
+struct Account;
+
+#[async_trait]
+impl CommandHandler for Account {
+    fn name(&self) -> &str {
+        "account"
+    }
+
+    fn args(&self) -> &str {
+        "delete <your name>"
+    }
+
+    fn help(&self) -> &str {
+        "deletes your account and anonymizes your messages"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let action = args.word()?;
+        if action != "delete" {
+            return Err(CommandError::Invalid(format!(
+                "Unknown action {action}, use .account delete <your name>"
+            )));
+        }
+        // Typing the name guards against deleting the account by accident
+        if args.rest()? != session.name() {
+            return Err(CommandError::Invalid(String::from(
+                "Type your own name to confirm, e.g. .account delete alice",
+            )));
+        }
+        session.delete_account()
+    }
+}
+
+/// Changes the password of the session's user.
+struct Passwd;
+
+#[async_trait]
+impl CommandHandler for Passwd {
+    fn name(&self) -> &str {
+        "passwd"
+    }
+
+    fn args(&self) -> &str {
+        "<current password> <new password>"
+    }
+
+    fn help(&self) -> &str {
+        "changes the password you log in with"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let current = args.word()?;
+        session.change_password(current, args.rest()?).await
+    }
+}
+
+/// Manages the accounts of the other users, for admins only.
+struct User;
+
+#[async_trait]
+impl CommandHandler for User {
+    fn name(&self) -> &str {
+        "user"
+    }
+
+    fn args(&self) -> &str {
+        "add <name> <password> | remove <name> | disable <name>"
+    }
+
+    fn help(&self) -> &str {
+        "manages the accounts, admins only"
+    }
+
+    async fn run(
+        &self,
+        session: &mut Session,
+        mut args: Args<'_>,
+    ) -> Result<MessageType, CommandError> {
+        let action = args.word()?;
+        let user = args.word()?;
+        session.manage_account(action, user, args.rest().ok()).await
+    }
+}
+
+/// Applies one of the text transformations, e.g. `.slugify Hello World`.
+struct Transform(Transformation);
+
+#[async_trait]
+impl CommandHandler for Transform {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn args(&self) -> &str {
+        "<text>"
+    }
+
+    fn help(&self) -> &str {
+        match self.0 {
+            Transformation::Lowercase => "converts the text to lowercase",
+            Transformation::Uppercase => "converts the text to uppercase",
+            Transformation::NoSpaces => "removes the spaces from the text",
+            Transformation::Slugify => "converts the text to a slug",
+            Transformation::Csv => "formats the CSV text as a table",
+            Transformation::Double => "repeats the text twice",
+            Transformation::Reverse => "reverses the text",
+        }
+    }
+
+    async fn run(&self, _: &mut Session, args: Args<'_>) -> Result<MessageType, CommandError> {
+        // The text itself is transformed, files of the server are never read
+        transform(args.rest()?, self.0, AppType::Unattended)
+            .map(MessageType::Text)
+            .map_err(|e| CommandError::Invalid(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::hub::Hub;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_args_parsing() {
+        let mut args = Args::new("  1a2b3c4d  new  text ");
+        assert_eq!(args.word().unwrap(), "1a2b3c4d");
+        assert_eq!(args.rest().unwrap(), "new  text");
+        let mut args = Args::new("@1024 big file.bin");
+        assert_eq!(args.offset().unwrap(), 1024);
+        assert_eq!(args.rest().unwrap(), "big file.bin");
+        assert_eq!(Args::new("file.bin").offset().unwrap(), 0);
+        assert!(matches!(
+            Args::new("@-1 file.bin").offset(),
+            Err(CommandError::Invalid(_))
+        ));
+        assert!(matches!(
+            Args::new("").word(),
+            Err(CommandError::MissingArgument)
+        ));
+        assert!(matches!(
+            Args::new("offline").parse::<Status>(),
+            Err(CommandError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_help_lists_registered_commands() {
+        let help = CommandRegistry::default().help();
+        assert!(help.contains(".join <room>"));
+        assert!(help.contains(".edit <id> <new text>"));
+
+        let mut commands = CommandRegistry::empty();
+        commands.register(Quit);
+        assert_eq!(
+            commands.help(),
+            "Available commands:\n.quit  terminates the connection"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transformations() {
+        let commands = CommandRegistry::default();
+        let mut session = Session::join(Arc::new(Hub::default()), String::from("alice"));
+        for (input, expected) in [
+            (".slugify Hello World", "hello-world"),
+            (".uppercase Hello", "HELLO"),
+        ] {
+            let response = commands.run(&mut session, input).await.unwrap();
+            assert!(matches!(response, MessageType::Text(text) if text == expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refusals_are_typed() {
+        let commands = CommandRegistry::default();
+        let mut session = Session::join(Arc::new(Hub::default()), String::from("alice"));
+        for (input, expected) in [
+            (".reverse", RefusalKind::Usage),
+            (".teleport", RefusalKind::UnknownCommand),
+            (".status offline", RefusalKind::InvalidArgument),
+            (".account delete bob", RefusalKind::InvalidArgument),
+            (".get ../Cargo.toml", RefusalKind::NotPermitted),
+            (".key carol", RefusalKind::InvalidArgument),
+            (".delete 0000", RefusalKind::InvalidArgument),
+            (".file no-such-file.txt", RefusalKind::InvalidArgument),
+            (".image Cargo.toml", RefusalKind::InvalidArgument),
+        ] {
+            let response = commands.run(&mut session, input).await.unwrap();
+            assert!(matches!(response, MessageType::Error { kind, .. } if kind == expected));
+        }
+        let response = commands.run(&mut session, ".reverse").await.unwrap();
+        assert!(
+            matches!(response, MessageType::Error { message, .. } if message == "Usage: .reverse <text>")
+        );
+        let response = commands
+            .run(&mut session, ".account delete alice")
+            .await
+            .unwrap();
+        assert!(matches!(response, MessageType::Quit));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::common::listing::FileEntry;
+use crate::common::MessageType;
+use crate::server::commands::CommandError;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use tokio::fs;
+
+/// Directory whose files the clients may browse and download, nothing outside it is served.
+#[derive(Debug, Clone)]
+pub struct ServedDir {
+    root: PathBuf,
+}
+
+impl Default for ServedDir {
+    /// Serves the working directory of the server.
+    fn default() -> Self {
+        ServedDir::new(".")
+    }
+}
+
+impl ServedDir {
+    /// Serves the files under the directory.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        ServedDir { root: root.into() }
+    }
+
+    /// Returns the served directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Lists the directory, sorted by name.
+    pub async fn list(&self, dir: &str) -> Result<MessageType, CommandError> {
+        let (path, shown) = self.resolve(dir).await?;
+        let metadata = fs::metadata(&path)
+            .await
+            .map_err(|e| unreadable(&shown, e))?;
+        if !metadata.is_dir() {
+            return Err(CommandError::Invalid(format!("{shown} is not a directory")));
+        }
+        let mut read = fs::read_dir(&path)
+            .await
+            .map_err(|e| unreadable(&shown, e))?;
+        let mut entries = Vec::new();
+        while let Some(entry) = read.next_entry().await.map_err(|e| unreadable(&shown, e))? {
+            // Links are listed as such, not as what they point to
+            let metadata = entry.metadata().await.map_err(|e| unreadable(&shown, e))?;
+            entries.push(FileEntry::new(
+                &entry.file_name().to_string_lossy(),
+                &metadata,
+            ));
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(MessageType::Listing {
+            dir: shown,
+            entries,
+        })
+    }
+
+    /// Returns the metadata of the file or directory.
+    pub async fn stat(&self, path: &str) -> Result<MessageType, CommandError> {
+        let (path, shown) = self.resolve(path).await?;
+        let metadata = fs::metadata(&path)
+            .await
+            .map_err(|e| unreadable(&shown, e))?;
+        Ok(MessageType::Stat(FileEntry::new(&shown, &metadata)))
+    }
+
+    /// Reads the file to be sent to the client, from the offset on.
+    pub async fn get(&self, path: &str, offset: u64) -> Result<MessageType, CommandError> {
+        let (path, shown) = self.resolve(path).await?;
+        if path.is_dir() {
+            return Err(CommandError::Invalid(format!(
+                "{shown} is a directory, use .ls {shown}"
+            )));
+        }
+        Ok(MessageType::from_file_range(&path, offset).await)
+    }
+
+    /// Resolves the path typed by the client, returns it with the path shown to the client.
+    ///
+    /// Paths start at the served directory even with a leading slash. `..` and links are resolved
+    /// before checking the path, so that neither can lead outside.
+    async fn resolve(&self, requested: &str) -> Result<(PathBuf, String), CommandError> {
+        let relative = Path::new(requested.trim().trim_start_matches('/'));
+        // Climbing above the root is refused before touching the file system, so that the
+        // existence of the files outside isn't revealed
+        let mut depth = 0;
+        for component in relative.components() {
+            match component {
+                Component::Normal(_) => depth += 1,
+                Component::ParentDir if depth == 0 => return Err(outside(requested)),
+                Component::ParentDir => depth -= 1,
+                _ => {}
+            }
+        }
+        let root = fs::canonicalize(&self.root)
+            .await
+            .map_err(|e| anyhow::Error::new(e).context("Served directory is unavailable"))?;
+        let path = match fs::canonicalize(root.join(relative)).await {
+            Ok(path) => path,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(CommandError::Invalid(format!(
+                    "No such file or directory: {requested}"
+                )))
+            }
+            Err(e) => return Err(unreadable(requested, e)),
+        };
+        let Ok(inside) = path.strip_prefix(&root) else {
+            return Err(outside(requested));
+        };
+        let shown = format!("/{}", inside.display());
+        Ok((path, shown))
+    }
+}
+
+/// Refuses a path leading outside the served directory.
+fn outside(requested: &str) -> CommandError {
+    CommandError::NotPermitted(format!("{requested} is outside the served directory"))
+}
+
+/// Refuses a path that can't be read.
+fn unreadable(shown: &str, e: io::Error) -> CommandError {
+    CommandError::Invalid(format!("Can't read {shown}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::crypto;
+    use crate::common::listing::EntryKind;
+    use crate::common::RefusalKind;
+
+    fn served() -> ServedDir {
+        ServedDir::new(env!("CARGO_MANIFEST_DIR"))
+    }
+
+    #[tokio::test]
+    async fn test_list_and_stat() {
+        let MessageType::Listing { dir, entries } = served().list("/src").await.unwrap() else {
+            panic!("Expected a listing");
+        };
+        assert_eq!(dir, "/src");
+        let lib = entries.iter().find(|entry| entry.name == "lib.rs").unwrap();
+        assert_eq!(lib.kind, EntryKind::File);
+        assert!(entries.windows(2).all(|pair| pair[0].name < pair[1].name));
+
+        let MessageType::Stat(entry) = served().stat("src/../Cargo.toml").await.unwrap() else {
+            panic!("Expected metadata");
+        };
+        assert_eq!(entry.name, "/Cargo.toml");
+        assert!(entry.size > 0 && entry.modified.is_some());
+        assert!(matches!(
+            served().list("Cargo.toml").await,
+            Err(CommandError::Invalid(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paths_outside_are_refused() {
+        for path in ["..", "/../Cargo.toml", "src/../../hw-l15-async"] {
+            assert!(matches!(
+                served().get(path, 0).await,
+                Err(CommandError::NotPermitted(_))
+            ));
+        }
+        assert!(matches!(
+            served().stat("missing.txt").await,
+            Err(CommandError::Invalid(_))
+        ));
+        let response = served().get("/Cargo.toml", 0).await.unwrap();
+        assert!(matches!(response, MessageType::File { name, .. } if name == "Cargo.toml"));
+    }
+
+    #[tokio::test]
+    async fn test_get_from_offset() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml");
+        let whole = std::fs::read(&path).unwrap();
+        let MessageType::FilePart {
+            name,
+            offset,
+            total,
+            content,
+            sha256,
+        } = served().get("Cargo.toml", 10).await.unwrap()
+        else {
+            panic!("Expected a part");
+        };
+        assert_eq!(
+            (name.as_str(), offset, total),
+            ("Cargo.toml", 10, whole.len() as u64)
+        );
+        assert_eq!(content, whole[10..]);
+        // The last part carries the hash of the whole file
+        assert_eq!(sha256, Some(crypto::file_hash(&path).await.unwrap()));
+        let beyond = served().get("Cargo.toml", total + 1).await.unwrap();
+        let MessageType::Error { kind, message } = beyond else {
+            panic!("Expected a refusal");
+        };
+        assert_eq!(kind, RefusalKind::InvalidArgument);
+        assert!(message.contains("beyond the end"));
+    }
+}
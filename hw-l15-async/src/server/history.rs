@@ -0,0 +1,227 @@
+use crate::common::envelope::Envelope;
+use crate::common::MessageType;
+use crate::server::commands::CommandError;
+use chrono::{DateTime, Utc};
+use log::info;
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Number of messages kept per room, the oldest can no longer be edited.
+const HISTORY_CAPACITY: usize = 256;
+/// Shortest ID prefix accepted by `.edit` and `.delete`.
+const MIN_PREFIX_LEN: usize = 4;
+/// Author shown instead of a user who deleted their account.
+pub const DELETED_USER: &str = "deleted user";
+
+/// Previous version of an edited or deleted message.
+#[derive(Debug, Clone)]
+pub struct Revision {
+    pub at: DateTime<Utc>,
+    pub text: String,
+}
+
+/// Text message published to a room, with its audit trail.
+#[derive(Debug)]
+pub struct Posted {
+    pub envelope: Envelope,
+    /// When the server recorded the message, whatever time its envelope says.
+    pub received: DateTime<Utc>,
+    pub revisions: Vec<Revision>,
+    pub deleted: bool,
+}
+
+/// Recent text messages of every room, by the room name.
+#[derive(Default)]
+pub struct History {
+    rooms: HashMap<String, VecDeque<Posted>>,
+}
+
+impl History {
+    /// Remembers a text message published to the room.
+    pub fn record(&mut self, room: &str, envelope: &Envelope) {
+        let history = self.rooms.entry(room.to_string()).or_default();
+        if history.len() == HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(Posted {
+            envelope: envelope.clone(),
+            received: Utc::now(),
+            revisions: Vec::new(),
+            deleted: false,
+        });
+    }
+
+    /// Replaces the text of the author's message, or deletes it if there's no new text.
+    ///
+    /// Returns the room and the full ID of the message, or why the change was refused.
+    pub fn revise(
+        &mut self,
+        author: &str,
+        prefix: &str,
+        text: Option<&str>,
+    ) -> Result<(String, Uuid), CommandError> {
+        let prefix = prefix.to_lowercase().replace('-', "");
+        if prefix.len() < MIN_PREFIX_LEN {
+            return Err(CommandError::Invalid(format!(
+                "Message ID has to have at least {MIN_PREFIX_LEN} characters"
+            )));
+        }
+        let mut found = self.rooms.iter_mut().flat_map(|(room, history)| {
+            history
+                .iter_mut()
+                .filter(|posted| posted.envelope.id.simple().to_string().starts_with(&prefix))
+                .map(move |posted| (room, posted))
+        });
+        let (Some((room, posted)), None) = (found.next(), found.next()) else {
+            return Err(CommandError::Invalid(format!(
+                "No single message matches the ID {prefix}"
+            )));
+        };
+        // Only the author may change the message
+        if posted.envelope.sender != author {
+            return Err(CommandError::NotPermitted(String::from(
+                "Only your own messages can be changed",
+            )));
+        }
+        if posted.deleted {
+            return Err(CommandError::Invalid(String::from(
+                "The message was deleted",
+            )));
+        }
+
+        let MessageType::Text(ref mut current) = posted.envelope.message else {
+            return Err(CommandError::Invalid(String::from(
+                "Only text messages can be changed",
+            )));
+        };
+        posted.revisions.push(Revision {
+            at: Utc::now(),
+            text: current.clone(),
+        });
+        let id = posted.envelope.id;
+        match text {
+            Some(text) => {
+                info!(target: "audit", "{author} edited {id} in {room}: {current:?} -> {text:?}");
+                *current = text.to_string();
+            }
+            None => {
+                info!(target: "audit", "{author} deleted {id} in {room}: {current:?}");
+                current.clear();
+                posted.deleted = true;
+            }
+        }
+        Ok((room.clone(), id))
+    }
+
+    /// Removes the author from their messages, returns how many were anonymized.
+    pub fn anonymize(&mut self, author: &str) -> usize {
+        let mut anonymized = 0;
+        for posted in self.rooms.values_mut().flatten() {
+            if posted.envelope.sender == author {
+                posted.envelope.sender = DELETED_USER.to_string();
+                anonymized += 1;
+            }
+        }
+        info!(target: "audit", "Anonymized {anonymized} messages of {author}");
+        anonymized
+    }
+
+    /// Returns the messages others posted to the room after the time, oldest first.
+    ///
+    /// The time the server received them counts, so that a skewed clock of the sender can't hide
+    /// a message or show it twice.
+    pub fn since(&self, room: &str, since: DateTime<Utc>, user: &str) -> Vec<Envelope> {
+        let Some(history) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        history
+            .iter()
+            .filter(|posted| !posted.deleted && posted.received > since)
+            .filter(|posted| posted.envelope.sender != user)
+            .map(|posted| posted.envelope.clone())
+            .collect()
+    }
+
+    /// Returns the message with its audit trail.
+    pub fn get(&self, id: Uuid) -> Option<&Posted> {
+        self.rooms
+            .values()
+            .flatten()
+            .find(|posted| posted.envelope.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::envelope::short_id;
+
+    #[test]
+    fn test_edit_and_delete_keep_audit() {
+        let mut history = History::default();
+        let envelope = Envelope::new("alice", MessageType::from_text("helo"));
+        history.record("lobby", &envelope);
+        let prefix = short_id(&envelope.id);
+
+        assert!(history.revise("bob", &prefix, Some("hacked")).is_err());
+        assert_eq!(
+            history.revise("alice", &prefix, Some("hello")).unwrap(),
+            (String::from("lobby"), envelope.id)
+        );
+        history.revise("alice", &prefix, None).unwrap();
+        assert!(history.revise("alice", &prefix, Some("again")).is_err());
+
+        let posted = history.get(envelope.id).unwrap();
+        assert!(posted.deleted);
+        let texts: Vec<&str> = posted.revisions.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, vec!["helo", "hello"]);
+    }
+
+    #[test]
+    fn test_anonymize_author() {
+        let mut history = History::default();
+        let envelope = Envelope::new("alice", MessageType::from_text("hi"));
+        history.record("lobby", &envelope);
+        history.record(
+            "lobby",
+            &Envelope::new("bob", MessageType::from_text("hey")),
+        );
+        assert_eq!(history.anonymize("alice"), 1);
+        assert_eq!(
+            history.get(envelope.id).unwrap().envelope.sender,
+            DELETED_USER
+        );
+        // The former author can no longer change the message
+        assert!(history
+            .revise("alice", &short_id(&envelope.id), None)
+            .is_err());
+    }
+
+    #[test]
+    fn test_since_uses_the_receive_time() {
+        let mut history = History::default();
+        let since = Utc::now();
+        // The clock of the sender is an hour behind, the message is still new
+        let mut late = Envelope::new("alice", MessageType::from_text("late clock"));
+        late.timestamp = since - chrono::Duration::hours(1);
+        history.record("lobby", &late);
+        let missed = history.since("lobby", since, "bob");
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].id, late.id);
+        // An hour ahead doesn't make an old message new
+        let mut early = Envelope::new("alice", MessageType::from_text("early clock"));
+        early.timestamp = Utc::now() + chrono::Duration::hours(1);
+        history.record("lobby", &early);
+        assert!(history.since("lobby", Utc::now(), "bob").is_empty());
+    }
+
+    #[test]
+    fn test_short_prefix_is_refused() {
+        let mut history = History::default();
+        history.record(
+            "lobby",
+            &Envelope::new("alice", MessageType::from_text("hi")),
+        );
+        assert!(history.revise("alice", "", Some("x")).is_err());
+    }
+}
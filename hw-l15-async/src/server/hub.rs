@@ -0,0 +1,740 @@
+use crate::common::envelope::{Envelope, SERVER_SENDER};
+use crate::common::handshake::PROTOCOL_VERSION;
+use crate::common::presence::Status;
+use crate::common::{MessageType, RefusalKind};
+use crate::server::accounts::{hash_password, hashing, AccountError, Accounts};
+use crate::server::bots::{BotGrant, Bots};
+use crate::server::commands::{CommandError, CommandRegistry};
+use crate::server::files::ServedDir;
+use crate::server::history::History;
+use crate::server::mailbox::{MailboxConfig, Mailboxes};
+use crate::server::resume::SessionTokens;
+use crate::server::Admission;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+/// Room every session joins after connecting.
+pub const DEFAULT_ROOM: &str = "lobby";
+/// Number of messages a slow session may fall behind before it starts losing them.
+const ROOM_CAPACITY: usize = 64;
+
+/// Message published to a room, tagged with the session that sent it.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub from_id: u64,
+    pub envelope: Arc<Envelope>,
+}
+
+/// Rooms and broadcast fabric shared by all connected sessions, regardless of transport.
+#[derive(Default)]
+pub struct Hub {
+    rooms: Mutex<HashMap<String, Sender<Broadcast>>>,
+    /// Direct delivery to every session of a user.
+    inboxes: Mutex<HashMap<String, HashMap<u64, UnboundedSender<Broadcast>>>>,
+    /// Public keys published by the users for end-to-end encryption.
+    keys: Mutex<HashMap<String, [u8; 32]>>,
+    /// Messages waiting for the users to acknowledge them.
+    mailboxes: Mutex<Mailboxes>,
+    /// Recent text messages of the rooms, so that their authors can change them.
+    history: Mutex<History>,
+    /// Users that connected at least once.
+    users: Mutex<HashSet<String>>,
+    /// API tokens of the bots.
+    bots: Mutex<Bots>,
+    /// Users who log in with a password.
+    accounts: Mutex<Accounts>,
+    /// Tokens of the sessions the clients can resume.
+    sessions: Mutex<SessionTokens>,
+    /// Commands the clients can run.
+    commands: CommandRegistry,
+    /// Files the clients can browse and download.
+    served: ServedDir,
+    next_id: AtomicU64,
+}
+
+impl Hub {
+    /// Creates an empty hub keeping the undelivered messages within the limits.
+    pub fn new(config: MailboxConfig, commands: CommandRegistry) -> Self {
+        Hub {
+            mailboxes: Mutex::new(Mailboxes::new(config)),
+            commands,
+            ..Self::default()
+        }
+    }
+
+    /// Keeps the undelivered messages in the given mailboxes, e.g. ones loaded from the disk.
+    pub fn with_mailboxes(mut self, mailboxes: Mailboxes) -> Self {
+        self.mailboxes = Mutex::new(mailboxes);
+        self
+    }
+
+    /// Lets the clients resume their sessions within the TTL after disconnecting.
+    pub fn with_session_ttl(mut self, ttl: Duration) -> Self {
+        self.sessions = Mutex::new(SessionTokens::new(ttl));
+        self
+    }
+
+    /// Serves the files under the directory to `.ls`, `.stat` and `.get`.
+    pub fn with_served_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.served = ServedDir::new(dir);
+        self
+    }
+
+    /// Returns the tokens of the sessions the clients can resume.
+    pub(crate) fn sessions(&self) -> MutexGuard<'_, SessionTokens> {
+        self.sessions.lock().unwrap()
+    }
+
+    /// Returns the bot tokens, to issue, list and revoke them.
+    pub fn bots(&self) -> MutexGuard<'_, Bots> {
+        self.bots.lock().unwrap()
+    }
+
+    /// Returns the accounts, to add and list them.
+    pub fn accounts(&self) -> MutexGuard<'_, Accounts> {
+        self.accounts.lock().unwrap()
+    }
+
+    /// Removes the account, its sessions end and can't be resumed.
+    pub fn remove_account(&self, name: &str) -> Result<(), AccountError> {
+        self.accounts().remove(name)?;
+        self.sessions().revoke(name);
+        Ok(())
+    }
+
+    /// Disables the account, its sessions end and can't be resumed.
+    pub fn disable_account(&self, name: &str) -> Result<(), AccountError> {
+        self.accounts().disable(name)?;
+        self.sessions().revoke(name);
+        Ok(())
+    }
+
+    /// Subscribes to a room, creating it if it doesn't exist yet.
+    fn subscribe(&self, room: &str) -> Receiver<Broadcast> {
+        let mut rooms = self.rooms.lock().unwrap();
+        rooms
+            .entry(room.to_string())
+            .or_insert_with(|| broadcast::channel(ROOM_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Publishes a message to every session in the room.
+    fn publish(&self, room: &str, broadcast: Broadcast) {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(sender) = rooms.get(room) {
+            // Sending only fails when nobody listens, which is fine for a chat room
+            let _ = sender.send(broadcast);
+        }
+    }
+
+    /// Delivers a message to every session of the user, returns false if the user is offline.
+    fn deliver(&self, user: &str, broadcast: Broadcast) -> bool {
+        let inboxes = self.inboxes.lock().unwrap();
+        let Some(sessions) = inboxes.get(user) else {
+            return false;
+        };
+        sessions
+            .values()
+            .filter(|inbox| inbox.send(broadcast.clone()).is_ok())
+            .count()
+            > 0
+    }
+
+    /// Remembers the public key of the user, replacing the previous one.
+    fn publish_key(&self, user: &str, key: [u8; 32]) {
+        self.keys.lock().unwrap().insert(user.to_string(), key);
+    }
+
+    /// Returns the public key published by the user.
+    fn public_key(&self, user: &str) -> Option<[u8; 32]> {
+        self.keys.lock().unwrap().get(user).copied()
+    }
+
+    /// Keeps a message for the user until it's acknowledged.
+    fn store(&self, user: &str, envelope: &Envelope) {
+        if envelope.needs_ack() {
+            self.mailboxes.lock().unwrap().push(user, envelope);
+        }
+    }
+
+    /// Forgets a message acknowledged by the user.
+    fn acknowledge(&self, user: &str, id: Uuid) {
+        self.mailboxes.lock().unwrap().remove(user, id);
+    }
+
+    /// Returns the messages the user hasn't acknowledged yet, oldest first.
+    fn pending(&self, user: &str) -> Vec<Envelope> {
+        self.mailboxes.lock().unwrap().pending(user)
+    }
+
+    /// Returns whether the user has connected at least once.
+    fn is_known(&self, user: &str) -> bool {
+        self.users.lock().unwrap().contains(user)
+    }
+
+    /// Forgets the key, mailbox, name, password and session tokens of the user, their room
+    /// messages stay anonymized.
+    fn forget(&self, user: &str) {
+        // Users without a password have no account to remove
+        let _ = self.accounts().remove(user);
+        self.keys.lock().unwrap().remove(user);
+        self.mailboxes.lock().unwrap().clear(user);
+        self.users.lock().unwrap().remove(user);
+        self.history.lock().unwrap().anonymize(user);
+        self.sessions().revoke(user);
+    }
+
+    /// Returns whether the user has an open session.
+    fn is_online(&self, user: &str) -> bool {
+        self.inboxes.lock().unwrap().contains_key(user)
+    }
+}
+
+/// Membership of a single connected client in the hub.
+pub struct Session {
+    id: u64,
+    name: String,
+    room: String,
+    hub: Arc<Hub>,
+    receiver: Receiver<Broadcast>,
+    inbox: UnboundedReceiver<Broadcast>,
+    status: Status,
+    /// Grant of the token the session authenticated with, if it's a bot.
+    bot: Option<BotGrant>,
+    /// Whether the session logged into an account with a password.
+    account: bool,
+    /// Key of the session token the client can resume the session with.
+    key: Option<[u8; 32]>,
+    /// Room messages posted after this time were missed before the session was resumed.
+    missed_since: Option<DateTime<Utc>>,
+    /// Protocol version agreed in the handshake, the answers are downgraded to it.
+    version: u16,
+}
+
+impl Session {
+    /// Registers a new session in the default room.
+    pub fn join(hub: Arc<Hub>, name: String) -> Self {
+        Self::enter(hub, name, None, None)
+    }
+
+    /// Registers the session of a client accepted by the handshake, resuming its previous one.
+    pub(crate) fn admit(hub: Arc<Hub>, name: String, admission: Admission) -> Self {
+        let Admission {
+            bot,
+            account,
+            resumed,
+            key,
+            version,
+        } = admission;
+        let room = resumed.as_ref().and_then(|resumed| resumed.room.clone());
+        let mut session = Self::enter(hub, name, bot, room);
+        session.hub.sessions().moved(&key, &session.room);
+        session.key = Some(key);
+        session.account = account;
+        session.missed_since = resumed.map(|resumed| resumed.since);
+        session.version = version;
+        session
+    }
+
+    /// Registers a new session of a person or a bot, in the given room or the default one.
+    ///
+    /// Bots that may not post to the default room start in their first room.
+    fn enter(hub: Arc<Hub>, name: String, bot: Option<BotGrant>, room: Option<String>) -> Self {
+        let room = match (room, &bot) {
+            (Some(room), _) => room,
+            (None, Some(grant)) if !grant.allows(DEFAULT_ROOM) => grant.rooms[0].clone(),
+            (None, _) => DEFAULT_ROOM.to_string(),
+        };
+        let id = hub.next_id.fetch_add(1, Ordering::Relaxed);
+        let receiver = hub.subscribe(&room);
+        let (tx, inbox) = mpsc::unbounded_channel();
+        hub.users.lock().unwrap().insert(name.clone());
+        hub.inboxes
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default()
+            .insert(id, tx);
+        info!("{name} joined room {room}");
+        let session = Self {
+            id,
+            name,
+            room,
+            hub,
+            receiver,
+            inbox,
+            status: Status::Online,
+            bot,
+            account: false,
+            key: None,
+            missed_since: None,
+            version: PROTOCOL_VERSION,
+        };
+        session.announce(session.status);
+        session
+    }
+
+    /// Returns the messages sent while the user was offline or never acknowledged, oldest first.
+    ///
+    /// A resumed session gets the room messages it missed as well.
+    pub fn retransmit(&self) -> Vec<Envelope> {
+        let mut pending = self.hub.pending(&self.name);
+        if let Some(since) = self.missed_since {
+            let missed = self
+                .hub
+                .history
+                .lock()
+                .unwrap()
+                .since(&self.room, since, &self.name);
+            // Mentions of the user are in the mailbox already
+            let missed: Vec<Envelope> = missed
+                .into_iter()
+                .filter(|envelope| pending.iter().all(|stored| stored.id != envelope.id))
+                .map(|envelope| self.hand_out(relayed(envelope)))
+                .collect();
+            pending.extend(missed);
+        }
+        if !pending.is_empty() {
+            info!(
+                "Delivering {} stored messages to {}",
+                pending.len(),
+                self.name
+            );
+        }
+        pending
+    }
+
+    /// Handles a message sent by the client and returns the direct responses, in order.
+    pub async fn handle(&mut self, mut envelope: Envelope) -> Result<Vec<Envelope>> {
+        // The sender is always the session itself, nobody can write on behalf of others
+        envelope.sender = self.name.clone();
+        envelope.bot = self.is_bot();
+        // So are the ID and time, nobody can reuse the ID of another message or fake the time
+        let request = envelope.id;
+        envelope.id = Uuid::new_v4();
+        envelope.timestamp = Utc::now();
+        let id = envelope.id;
+        // Messages passed on to others are confirmed with their new ID
+        let mut accepted = false;
+        // A revoked token ends the session of the bot
+        if let Some(grant) = &self.bot {
+            if !self.hub.bots().is_active(grant.id) {
+                info!(
+                    "Token {} of bot {} was revoked, disconnecting",
+                    grant.id, self.name
+                );
+                return Ok(vec![Envelope::new(SERVER_SENDER, MessageType::Quit)]);
+            }
+        }
+        // So does removing or disabling the account of the user
+        if !self.hub.accounts().allows(&self.name, self.account) {
+            info!("Account of {} changed, disconnecting", self.name);
+            return Ok(vec![Envelope::new(SERVER_SENDER, MessageType::Quit)]);
+        }
+        let response = match envelope.message {
+            MessageType::Text(ref text) => {
+                if text.starts_with('.') {
+                    // Commands are answered only
+                    let hub = self.hub.clone();
+                    Some(hub.commands.run(self, text).await?)
+                } else {
+                    // Plain text is echoed and goes to the room as well
+                    let response = MessageType::from_text(text);
+                    self.hub
+                        .history
+                        .lock()
+                        .unwrap()
+                        .record(&self.room, &envelope);
+                    self.store_mentions(&envelope);
+                    self.publish(envelope);
+                    accepted = true;
+                    Some(response)
+                }
+            }
+            MessageType::Quit => Some(MessageType::Quit),
+            MessageType::File { .. } | MessageType::Image { .. } => {
+                // Files and images uploaded by the client are shared with the room, unless they
+                // were corrupted on the way
+                match envelope.message.verify() {
+                    Ok(()) => {
+                        self.publish(envelope);
+                        accepted = true;
+                        None
+                    }
+                    Err(e) => Some(MessageType::refusal(
+                        RefusalKind::InvalidArgument,
+                        e.to_string(),
+                    )),
+                }
+            }
+            MessageType::PublicKey { key, .. } => {
+                // Users can only publish their own key
+                info!("{} published a public key", self.name);
+                self.hub.publish_key(&self.name, key);
+                None
+            }
+            MessageType::Encrypted {
+                ref mut from,
+                ref to,
+                ..
+            } => {
+                *from = self.name.clone();
+                let to = to.clone();
+                if self.hub.deliver(&to, self.broadcast(envelope.clone())) {
+                    accepted = true;
+                    None
+                } else if self.hub.is_known(&to) {
+                    // Delivered when the recipient connects again
+                    self.hub.store(&to, &envelope);
+                    accepted = true;
+                    Some(MessageType::Text(format!(
+                        "User {to} is offline, the message will be delivered when they connect"
+                    )))
+                } else {
+                    Some(MessageType::refusal(
+                        RefusalKind::InvalidArgument,
+                        format!("User {to} is unknown"),
+                    ))
+                }
+            }
+            MessageType::Ack { id, ref to, state } => {
+                trace!("{} acknowledged {id} as {state:?}", self.name);
+                self.hub.acknowledge(&self.name, id);
+                // Receipts of messages sent by other users are passed on to them
+                if to != SERVER_SENDER {
+                    let to = to.clone();
+                    self.hub.deliver(&to, self.broadcast(envelope));
+                }
+                None
+            }
+            MessageType::Presence { status, .. } => match status {
+                // Only the server announces that a user went offline
+                Status::Offline => Some(MessageType::refusal(
+                    RefusalKind::InvalidArgument,
+                    "Offline can't be set",
+                )),
+                status => {
+                    self.set_status(status);
+                    None
+                }
+            },
+            MessageType::Typing { .. } => {
+                // Fanned out right away, never stored
+                let typing = MessageType::Typing {
+                    user: self.name.clone(),
+                };
+                self.publish(Envelope::new(&self.name, typing));
+                None
+            }
+            // Changes are requested with .edit and .delete, so that the server checks the author
+            MessageType::Edit { .. } | MessageType::Delete { .. } => Some(MessageType::refusal(
+                RefusalKind::Usage,
+                "Use .edit <id> <new text> or .delete <id> to change a message",
+            )),
+            // Decompressed when received, and only the server confirms, refuses, moves sessions or
+            // lists its files
+            MessageType::Compressed(_)
+            | MessageType::Accepted { .. }
+            | MessageType::Joined { .. }
+            | MessageType::Error { .. }
+            | MessageType::Listing { .. }
+            | MessageType::Stat(_)
+            | MessageType::FilePart { .. } => None,
+        };
+        let accepted = accepted.then_some(MessageType::Accepted { request, id });
+        Ok(accepted
+            .into_iter()
+            .chain(response)
+            .filter_map(|message| message.downgrade(self.version))
+            .map(|message| self.hand_out(Envelope::new(SERVER_SENDER, message)))
+            .collect())
+    }
+
+    /// Returns the name of the session's user.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether the session authenticated with a bot token.
+    pub fn is_bot(&self) -> bool {
+        self.bot.is_some()
+    }
+
+    /// Returns the room the session is in.
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    /// Returns the commands the session can run.
+    pub fn commands(&self) -> &CommandRegistry {
+        &self.hub.commands
+    }
+
+    /// Returns the directory the session may browse and download from.
+    pub fn served(&self) -> &ServedDir {
+        &self.hub.served
+    }
+
+    /// Returns the public key published by the user.
+    pub fn public_key(&self, user: &str) -> Option<[u8; 32]> {
+        self.hub.public_key(user)
+    }
+
+    /// Waits for the next direct message or message published to the room by another session.
+    pub async fn recv(&mut self) -> Envelope {
+        loop {
+            let received = tokio::select! {
+                received = self.receiver.recv() => received,
+                Some(direct) = self.inbox.recv() => {
+                    let envelope = Arc::unwrap_or_clone(direct.envelope);
+                    trace!("Delivering direct message from {} to {}", envelope.sender, self.name);
+                    return self.hand_out(envelope);
+                }
+            };
+            match received {
+                Ok(broadcast) if broadcast.from_id == self.id => continue,
+                Ok(broadcast) => {
+                    let envelope = Arc::unwrap_or_clone(broadcast.envelope);
+                    trace!("Relaying message from {} to {}", envelope.sender, self.name);
+                    return self.hand_out(relayed(envelope));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "{} missed {skipped} messages in room {}",
+                        self.name, self.room
+                    );
+                }
+                Err(RecvError::Closed) => {
+                    // The hub keeps every room's sender alive, so re-subscribe just in case
+                    self.receiver = self.hub.subscribe(&self.room);
+                }
+            }
+        }
+    }
+
+    /// Edits or deletes a message of this session's user and lets everyone in its room know.
+    pub fn revise(&self, prefix: &str, text: Option<&str>) -> Result<MessageType, CommandError> {
+        let (room, id) = self
+            .hub
+            .history
+            .lock()
+            .unwrap()
+            .revise(&self.name, prefix, text)?;
+        // Copies waiting for offline users change as well
+        self.hub.mailboxes.lock().unwrap().revise(id, text);
+        let change = match text {
+            Some(text) => MessageType::Edit {
+                id,
+                text: text.to_string(),
+            },
+            None => MessageType::Delete { id },
+        };
+        let envelope = Envelope::new(&self.name, change.clone());
+        self.hub.publish(&room, self.broadcast(envelope));
+        Ok(change)
+    }
+
+    /// Keeps the room or direct message delivered to the client until it's acknowledged.
+    ///
+    /// Responses of the server answer a request of this connection, e.g. a whole file, so they
+    /// are asked for again instead of being replayed to the next one.
+    fn hand_out(&self, envelope: Envelope) -> Envelope {
+        if envelope.sender != SERVER_SENDER {
+            self.hub.store(&self.name, &envelope);
+        }
+        envelope
+    }
+
+    /// Stores the room message for the mentioned users who are offline.
+    fn store_mentions(&self, envelope: &Envelope) {
+        let MessageType::Text(ref text) = envelope.message else {
+            return;
+        };
+        let mentioned: HashSet<&str> = text
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .map(|user| user.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+            .collect();
+        for user in mentioned {
+            if user == self.name || !self.hub.is_known(user) || self.hub.is_online(user) {
+                continue;
+            }
+            trace!("Storing mention of offline {user} in room {}", self.room);
+            let mention = Envelope {
+                message: MessageType::Text(format!("[{} in {}] {text}", self.name, self.room)),
+                ..envelope.clone()
+            };
+            self.hub.store(user, &mention);
+        }
+    }
+
+    /// Deletes the account of the session's user, the connection is closed afterwards.
+    ///
+    /// Bots are removed by revoking their token instead.
+    pub fn delete_account(&mut self) -> Result<MessageType, CommandError> {
+        if self.is_bot() {
+            return Err(CommandError::NotPermitted(String::from(
+                "Bots are removed by revoking their token",
+            )));
+        }
+        info!(target: "audit", "{} deleted their account", self.name);
+        self.hub.forget(&self.name);
+        Ok(MessageType::Quit)
+    }
+
+    /// Replaces the password of the session's user, who has to know the current one.
+    pub async fn change_password(
+        &self,
+        current: &str,
+        new: &str,
+    ) -> Result<MessageType, CommandError> {
+        if !self.account {
+            return Err(CommandError::NotPermitted(String::from(
+                "You didn't log in with a password, ask an admin for an account",
+            )));
+        }
+        let Some(credentials) = self.hub.accounts().credentials(&self.name) else {
+            let unknown = AccountError::Unknown(self.name.clone());
+            return Err(CommandError::Invalid(unknown.to_string()));
+        };
+        // Argon2 is slow, the copied hash is checked and the new one made without the lock
+        let (checked, current, new) = (credentials.clone(), current.to_string(), new.to_string());
+        hashing(move || {
+            checked.verify(Some(&current))?;
+            hash_password(&new)
+        })
+        .await
+        .and_then(|hash| self.hub.accounts().replace_hash(&credentials, hash))
+        .map_err(|e| CommandError::Invalid(e.to_string()))?;
+        Ok(MessageType::from_text("Password changed"))
+    }
+
+    /// Adds, removes or disables the account of another user, only admins may.
+    pub async fn manage_account(
+        &self,
+        action: &str,
+        user: &str,
+        password: Option<&str>,
+    ) -> Result<MessageType, CommandError> {
+        if !self.account || !self.hub.accounts().is_admin(&self.name) {
+            return Err(CommandError::NotPermitted(String::from(
+                "Only admins can manage the accounts",
+            )));
+        }
+        let (managed, done) = match (action, password) {
+            ("add", Some(password)) => {
+                // Argon2 is slow, the password is hashed before the accounts are locked
+                let password = password.to_string();
+                let added = hashing(move || hash_password(&password))
+                    .await
+                    .and_then(|hash| self.hub.accounts().insert(user, hash, false));
+                (added, "Added")
+            }
+            ("add", None) => return Err(CommandError::MissingArgument),
+            ("remove", _) => (self.hub.remove_account(user), "Removed"),
+            ("disable", _) => (self.hub.disable_account(user), "Disabled"),
+            _ => {
+                return Err(CommandError::Invalid(format!(
+                    "Unknown action {action}, use add, remove or disable"
+                )))
+            }
+        };
+        managed.map_err(|e| CommandError::Invalid(e.to_string()))?;
+        info!(target: "audit", "{done} the account of {user} on behalf of {}", self.name);
+        Ok(MessageType::Text(format!("{done} the account of {user}")))
+    }
+
+    /// Moves the session to another room.
+    pub fn switch_room(&mut self, room: &str) -> Result<MessageType, CommandError> {
+        if room.is_empty() {
+            return Err(CommandError::Invalid(String::from(
+                "Room name can't be empty",
+            )));
+        }
+        if let Some(grant) = self.bot.as_ref().filter(|grant| !grant.allows(room)) {
+            return Err(CommandError::NotPermitted(format!(
+                "Bot {} can't join room {room}",
+                grant.name
+            )));
+        }
+        self.receiver = self.hub.subscribe(room);
+        if let Some(key) = &self.key {
+            self.hub.sessions().moved(key, room);
+        }
+        info!("{} moved from room {} to {room}", self.name, self.room);
+        self.room = room.to_string();
+        self.announce(self.status);
+        Ok(MessageType::Joined {
+            room: room.to_string(),
+        })
+    }
+
+    /// Changes the presence of the session and lets the room know.
+    pub fn set_status(&mut self, status: Status) {
+        info!("{} is {status}", self.name);
+        self.status = status;
+        self.announce(status);
+    }
+
+    /// Publishes the presence of the user to the current room.
+    fn announce(&self, status: Status) {
+        let presence = MessageType::Presence {
+            user: self.name.clone(),
+            status,
+            last_seen: (status == Status::Offline).then(Utc::now),
+        };
+        self.publish(Envelope::new(&self.name, presence));
+    }
+
+    /// Publishes a message to the current room.
+    fn publish(&self, envelope: Envelope) {
+        self.hub.publish(&self.room, self.broadcast(envelope));
+    }
+
+    /// Tags the message with this session as the sender.
+    fn broadcast(&self, mut envelope: Envelope) -> Broadcast {
+        // Presence, typing and changes of a bot are marked like its messages
+        envelope.bot = self.is_bot();
+        Broadcast {
+            from_id: self.id,
+            envelope: Arc::new(envelope),
+        }
+    }
+}
+
+/// Labels a room text with its sender, the envelope keeps its ID so the receipts find their way.
+fn relayed(mut envelope: Envelope) -> Envelope {
+    if let MessageType::Text(ref mut text) = envelope.message {
+        *text = format!("[{}] {}", envelope.sender, text);
+    }
+    envelope
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // The client has the TTL of the session token to resume it
+        if let Some(key) = &self.key {
+            self.hub.sessions().left(key);
+        }
+        // Stop delivering direct messages to the closed session
+        let mut inboxes = self.hub.inboxes.lock().unwrap();
+        if let Some(sessions) = inboxes.get_mut(&self.name) {
+            sessions.remove(&self.id);
+            if sessions.is_empty() {
+                inboxes.remove(&self.name);
+                drop(inboxes);
+                // The last session of the user is gone
+                self.announce(Status::Offline);
+            }
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use crate::common::envelope::Envelope;
+use crate::common::{LibError, MessageType};
+use chrono::{DateTime, Utc};
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Name of the log of the mailboxes in the data directory.
+const LOG_FILE: &str = "mailboxes.jsonl";
+/// Number of records the log may have beyond the stored messages before it's compacted.
+const LOG_SLACK: usize = 1024;
+
+/// Limits of the messages kept for a single user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MailboxConfig {
+    /// Number of messages kept per user, the oldest are dropped first.
+    pub capacity: usize,
+    /// Time after which an undelivered message is dropped.
+    pub expiry: Duration,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        MailboxConfig {
+            capacity: 256,
+            expiry: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Message waiting in a mailbox, with the time the server stored it.
+struct Stored {
+    since: DateTime<Utc>,
+    envelope: Envelope,
+}
+
+/// Change of the mailboxes, appended to the log as a JSON line.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Push {
+        user: String,
+        since: DateTime<Utc>,
+        envelope: Envelope,
+    },
+    Remove {
+        user: String,
+        id: Uuid,
+    },
+    Revise {
+        id: Uuid,
+        text: Option<String>,
+    },
+    Clear {
+        user: String,
+    },
+}
+
+/// Log the changes of the mailboxes are appended to, so that they survive a restart.
+struct Log {
+    path: PathBuf,
+    file: File,
+    /// Number of records in the file.
+    records: usize,
+}
+
+/// Messages kept for the users until they acknowledge them, oldest first.
+///
+/// Covers both the messages sent while the user was offline and those handed to a connection that
+/// never acknowledged them.
+#[derive(Default)]
+pub struct Mailboxes {
+    config: MailboxConfig,
+    boxes: HashMap<String, VecDeque<Stored>>,
+    /// Without a log the mailboxes live in memory only.
+    log: Option<Log>,
+}
+
+impl Mailboxes {
+    /// Creates empty mailboxes with the given limits, kept in memory only.
+    pub fn new(config: MailboxConfig) -> Self {
+        Mailboxes {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Loads the mailboxes logged in the directory and keeps logging their changes there.
+    pub fn open(config: MailboxConfig, dir: &Path) -> Result<Self, LibError> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOG_FILE);
+        let mut mailboxes = Mailboxes::new(config);
+        match fs::read_to_string(&path) {
+            Ok(log) => {
+                for line in log.lines() {
+                    match serde_json::from_str(line) {
+                        Ok(record) => mailboxes.apply(record),
+                        // A crash may cut the last record short
+                        Err(e) => warn!("Skipping a broken record of {}: {e}", path.display()),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        for mailbox in mailboxes.boxes.values_mut() {
+            expire(mailbox, config.expiry);
+        }
+        mailboxes.boxes.retain(|_, mailbox| !mailbox.is_empty());
+        let (file, records) = mailboxes.compact(&path)?;
+        info!("Loaded {records} stored messages from {}", path.display());
+        mailboxes.log = Some(Log {
+            path,
+            file,
+            records,
+        });
+        Ok(mailboxes)
+    }
+
+    /// Keeps the message for the user until it's acknowledged.
+    pub fn push(&mut self, user: &str, envelope: &Envelope) {
+        // Sessions of the same user receive the same room messages
+        if self.contains(user, envelope.id) {
+            return;
+        }
+        self.change(Record::Push {
+            user: user.to_string(),
+            since: Utc::now(),
+            envelope: envelope.clone(),
+        });
+    }
+
+    /// Removes a message acknowledged by the user.
+    pub fn remove(&mut self, user: &str, id: Uuid) {
+        // Messages that were never stored are acknowledged as well
+        if self.contains(user, id) {
+            self.change(Record::Remove {
+                user: user.to_string(),
+                id,
+            });
+        }
+    }
+
+    /// Applies an edit or a deletion to every stored copy of the message.
+    pub fn revise(&mut self, id: Uuid, text: Option<&str>) {
+        let stored = self
+            .boxes
+            .values()
+            .flatten()
+            .any(|stored| stored.envelope.id == id);
+        if stored {
+            self.change(Record::Revise {
+                id,
+                text: text.map(String::from),
+            });
+        }
+    }
+
+    /// Drops every message kept for the user.
+    pub fn clear(&mut self, user: &str) {
+        if self.boxes.contains_key(user) {
+            self.change(Record::Clear {
+                user: user.to_string(),
+            });
+        }
+    }
+
+    /// Returns the messages waiting for the user, oldest first.
+    pub fn pending(&mut self, user: &str) -> Vec<Envelope> {
+        let Some(mailbox) = self.boxes.get_mut(user) else {
+            return Vec::new();
+        };
+        expire(mailbox, self.config.expiry);
+        mailbox
+            .iter()
+            .map(|stored| stored.envelope.clone())
+            .collect()
+    }
+
+    /// Returns whether the message is stored for the user.
+    fn contains(&self, user: &str, id: Uuid) -> bool {
+        self.boxes
+            .get(user)
+            .is_some_and(|mailbox| mailbox.iter().any(|stored| stored.envelope.id == id))
+    }
+
+    /// Appends the change to the log, if there is one, and applies it.
+    fn change(&mut self, record: Record) {
+        if let Some(log) = &mut self.log {
+            let line = serde_json::to_string(&record).map_err(io::Error::from);
+            match line.and_then(|line| writeln!(log.file, "{line}")) {
+                Ok(()) => log.records += 1,
+                // The change still applies, it's only lost with a restart
+                Err(e) => warn!("Failed to log a change of the mailboxes: {e}"),
+            }
+        }
+        self.apply(record);
+        // Acknowledged messages stay in the log until it's rewritten
+        let stored: usize = self.boxes.values().map(VecDeque::len).sum();
+        let Some(path) = self
+            .log
+            .as_ref()
+            .filter(|log| log.records > stored * 2 + LOG_SLACK)
+            .map(|log| log.path.clone())
+        else {
+            return;
+        };
+        match self.compact(&path) {
+            Ok((file, records)) => {
+                self.log = Some(Log {
+                    path,
+                    file,
+                    records,
+                })
+            }
+            Err(e) => warn!("Failed to compact {}: {e}", path.display()),
+        }
+    }
+
+    /// Applies a change, whether it was just made or read from the log.
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Push {
+                user,
+                since,
+                envelope,
+            } => {
+                let (capacity, expiry) = (self.config.capacity, self.config.expiry);
+                let mailbox = self.boxes.entry(user.clone()).or_default();
+                if mailbox
+                    .iter()
+                    .any(|stored| stored.envelope.id == envelope.id)
+                {
+                    return;
+                }
+                expire(mailbox, expiry);
+                while mailbox.len() >= capacity.max(1) {
+                    warn!("Mailbox of {user} is full, dropping the oldest message");
+                    mailbox.pop_front();
+                }
+                mailbox.push_back(Stored { since, envelope });
+            }
+            Record::Remove { user, id } => {
+                if let Some(mailbox) = self.boxes.get_mut(&user) {
+                    mailbox.retain(|stored| stored.envelope.id != id);
+                    if mailbox.is_empty() {
+                        self.boxes.remove(&user);
+                    }
+                }
+            }
+            Record::Revise { id, text } => {
+                for mailbox in self.boxes.values_mut() {
+                    let Some(text) = &text else {
+                        mailbox.retain(|stored| stored.envelope.id != id);
+                        continue;
+                    };
+                    for stored in mailbox.iter_mut().filter(|stored| stored.envelope.id == id) {
+                        if let MessageType::Text(ref mut current) = stored.envelope.message {
+                            // Keep the label of the relayed message, e.g. "[alice] "
+                            let label = current.find("] ").map_or(0, |end| end + 2);
+                            *current = format!("{}{text} (edited)", &current[..label]);
+                        }
+                    }
+                }
+                self.boxes.retain(|_, mailbox| !mailbox.is_empty());
+            }
+            Record::Clear { user } => {
+                if let Some(mailbox) = self.boxes.remove(&user) {
+                    trace!("Dropped {} messages of {user}", mailbox.len());
+                }
+            }
+        }
+    }
+
+    /// Rewrites the log with only the stored messages, returns it opened for appending and the
+    /// number of its records.
+    fn compact(&self, path: &Path) -> io::Result<(File, usize)> {
+        let compacted = path.with_extension("jsonl.tmp");
+        let mut file = create_private(&compacted)?;
+        let mut records = 0;
+        for (user, mailbox) in &self.boxes {
+            for stored in mailbox {
+                let record = Record::Push {
+                    user: user.clone(),
+                    since: stored.since,
+                    envelope: stored.envelope.clone(),
+                };
+                writeln!(file, "{}", serde_json::to_string(&record)?)?;
+                records += 1;
+            }
+        }
+        file.sync_all()?;
+        // Replaced at once, a crash leaves either the old log or the new one
+        fs::rename(&compacted, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok((file, records))
+    }
+}
+
+/// Creates the file readable by the owner only, the stored messages may be private.
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Drops the messages stored for longer than the expiry.
+fn expire(mailbox: &mut VecDeque<Stored>, expiry: Duration) {
+    // Messages are stored in order, so the expired ones are at the front
+    while let Some(stored) = mailbox.front() {
+        let elapsed = (Utc::now() - stored.since).to_std().unwrap_or_default();
+        if elapsed < expiry {
+            return;
+        }
+        trace!("Message {} expired", stored.envelope.id);
+        mailbox.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> Envelope {
+        Envelope::new("alice", MessageType::from_text(text))
+    }
+
+    #[test]
+    fn test_capacity_keeps_newest_in_order() {
+        let mut mailboxes = Mailboxes::new(MailboxConfig {
+            capacity: 2,
+            ..MailboxConfig::default()
+        });
+        let (first, second, third) = (text("1"), text("2"), text("3"));
+        for envelope in [&first, &second, &second, &third] {
+            mailboxes.push("bob", envelope);
+        }
+        let ids: Vec<Uuid> = mailboxes.pending("bob").iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![second.id, third.id]);
+
+        mailboxes.remove("bob", second.id);
+        assert_eq!(mailboxes.pending("bob").len(), 1);
+        assert!(mailboxes.pending("carol").is_empty());
+    }
+
+    #[test]
+    fn test_revise_stored_copies() {
+        let mut mailboxes = Mailboxes::default();
+        let (edited, deleted) = (text("[alice] helo"), text("[alice] oops"));
+        mailboxes.push("bob", &edited);
+        mailboxes.push("bob", &deleted);
+        mailboxes.revise(edited.id, Some("hello"));
+        mailboxes.revise(deleted.id, None);
+
+        let pending = mailboxes.pending("bob");
+        assert_eq!(pending.len(), 1);
+        assert!(
+            matches!(&pending[0].message, MessageType::Text(text) if text == "[alice] hello (edited)")
+        );
+    }
+
+    #[test]
+    fn test_mailboxes_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("mailboxes-{}", Uuid::new_v4()));
+        let (kept, acked, edited) = (text("kept"), text("acked"), text("[alice] helo"));
+        let mut mailboxes = Mailboxes::open(MailboxConfig::default(), &dir).unwrap();
+        for envelope in [&kept, &acked, &edited] {
+            mailboxes.push("bob", envelope);
+        }
+        mailboxes.push("carol", &kept);
+        mailboxes.remove("bob", acked.id);
+        mailboxes.revise(edited.id, Some("hello"));
+        mailboxes.clear("carol");
+        drop(mailboxes);
+
+        let mut mailboxes = Mailboxes::open(MailboxConfig::default(), &dir).unwrap();
+        let pending = mailboxes.pending("bob");
+        let ids: Vec<Uuid> = pending.iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![kept.id, edited.id]);
+        let edited = "[alice] hello (edited)";
+        assert!(matches!(&pending[1].message, MessageType::Text(text) if text == edited));
+        assert!(mailboxes.pending("carol").is_empty());
+        // Loading rewrites the log with only the stored messages
+        let log = fs::read_to_string(dir.join(LOG_FILE)).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expired_messages_are_dropped() {
+        let mut mailboxes = Mailboxes::new(MailboxConfig {
+            expiry: Duration::ZERO,
+            ..MailboxConfig::default()
+        });
+        mailboxes.push("bob", &text("too late"));
+        assert!(mailboxes.pending("bob").is_empty());
+    }
+}
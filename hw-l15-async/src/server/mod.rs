@@ -1,20 +1,74 @@
-use crate::common::MessageType;
+pub mod accounts;
+pub mod bots;
+mod builder;
+pub mod commands;
+pub mod files;
+pub mod history;
+pub mod hub;
+pub mod mailbox;
+pub mod resume;
+mod ws;
+
+use crate::common::codec::{WireFormat, ALL_CODECS};
+use crate::common::envelope::{Envelope, SERVER_SENDER};
+use crate::common::handshake::{Feature, HandshakeReply, Hello, Welcome};
+use crate::common::{receive_loop, LibError, MessageType};
+use accounts::hashing;
 use anyhow::{bail, Context, Result};
+use bots::BotGrant;
+use builder::stopped;
+pub use builder::{Server, ServerBuilder, ServerConfig, ServerHandle};
+use hub::{Hub, Session};
 use log::{error, info, trace};
+use resume::Resumption;
+use std::future::Future;
+use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::path::Path;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 
-/// Starts the server with the specified IP and port.
-pub async fn start_server(ip: Ipv4Addr, port: u16) -> Result<()> {
-    // Create the server listener
-    let server = create_server(ip, port)
-        .await
-        .context("Failed to create server")?;
-    // Start the server loop to handle incoming connections
-    server_loop(server).await.context("Server loop crashed")?;
-    Ok(())
+/// Features the server can enable for a connection.
+pub(crate) const SERVER_FEATURES: &[Feature] = &[Feature::Compression];
+/// Time a client has to introduce itself after connecting.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listener accepting the native clients over one transport.
+pub(crate) trait Transport: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// Accepts a connection, returns it with a description of the peer.
+    fn accept_client(&self) -> impl Future<Output = io::Result<(Self::Stream, String)>> + Send;
+}
+
+impl Transport for TcpListener {
+    type Stream = TcpStream;
+
+    async fn accept_client(&self) -> io::Result<(TcpStream, String)> {
+        let (stream, peer_addr) = self.accept().await?;
+        Ok((stream, peer_addr.to_string()))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_client(&self) -> io::Result<(UnixStream, String)> {
+        let (stream, _) = self.accept().await?;
+        // Local peers have no address, the user they run as tells them apart
+        let peer = match stream.peer_cred() {
+            Ok(cred) => format!("unix socket peer with uid {}", cred.uid()),
+            Err(_) => String::from("unix socket peer"),
+        };
+        Ok((stream, peer))
+    }
 }
 
 /// Creates a TcpListener bound to the specified IP and port.
@@ -31,17 +85,27 @@ async fn create_server(ip: Ipv4Addr, port: u16) -> Result<TcpListener> {
 }
 
 /// Main loop to accept and handle incoming client connections.
-async fn server_loop(listener: TcpListener) -> Result<()> {
+async fn server_loop<T: Transport>(
+    listener: T,
+    hub: Arc<Hub>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut clients = JoinSet::new();
     loop {
-        match listener.accept().await {
-            Ok((stream, peer_addr)) => {
-                info!("Accepted connection from {:?}", peer_addr);
+        let accepted = tokio::select! {
+            accepted = listener.accept_client() => accepted,
+            _ = stopped(&mut shutdown) => break,
+        };
+        match accepted {
+            Ok((stream, peer)) => {
+                info!("Accepted connection from {peer}");
 
                 // Spawn a new task to handle each client connection
-                tokio::spawn(async move {
-                    match handle_client(stream).await {
-                        Ok(_) => info!("Client {:?} handled successfully", peer_addr),
-                        Err(e) => error!("Error handling client {:?}: {}", peer_addr, e),
+                let (hub, shutdown) = (hub.clone(), shutdown.clone());
+                clients.spawn(async move {
+                    match handle_client(stream, &peer, hub, shutdown).await {
+                        Ok(_) => info!("Client {peer} handled successfully"),
+                        Err(e) => error!("Error handling client {peer}: {e}"),
                     }
                 });
             }
@@ -50,59 +114,200 @@ async fn server_loop(listener: TcpListener) -> Result<()> {
             }
         }
     }
+    // Let the connected clients quit gracefully
+    while clients.join_next().await.is_some() {}
+    Ok(())
 }
 
 /// Handles communication with a single client.
-async fn handle_client(mut stream: TcpStream) -> Result<()> {
-    // Get the client's address
-    let peer = stream.peer_addr().unwrap();
+async fn handle_client<S>(
+    mut stream: S,
+    peer: &str,
+    hub: Arc<Hub>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Check the client speaks a compatible protocol
+    let Some((hello, welcome, admission)) = accept_handshake(&mut stream, &hub)
+        .await
+        .context("Handshake failed")?
+    else {
+        stream
+            .shutdown()
+            .await
+            .context("Failed to terminate connection")?;
+        return Ok(());
+    };
+    info!(
+        "Client {peer} introduced itself as {}, using {} codec and features {:?}",
+        hello.client_name, welcome.codec, welcome.features
+    );
+    let wire = WireFormat::from(&welcome);
+    let mut session = Session::admit(hub, hello.client_name, admission);
+
+    // Receive in a separate task, so that relaying never interrupts a partially received request
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (tx, mut requests) = mpsc::channel(32);
+    tokio::spawn(receive_loop(reader, wire, tx));
+    // Messages a previous connection of the user never acknowledged come first
+    for envelope in session.retransmit() {
+        envelope
+            .send(&mut writer, wire)
+            .await
+            .context("Retransmission failed")?;
+    }
     loop {
-        // Receive a request from the client
-        let request = receive_request(&mut stream).await.context("Request receiving failed")?;
-        trace!("Received request '{}' from client {}", request, peer);
-        // Create a response based on the request
-        let response = create_response(&request).await.context("Failed to create response")?;
-        trace!("Sending response to {peer}");
-        // Send the response to the client
-        response
-            .send(&mut stream).await.context("Response sending failed")?;
-        // Shutdown the connection if Quit message
-        if let MessageType::Quit = response {
-            info!("Shutting down connection with {peer}");
-            stream.shutdown().await.context("Failed to terminate connection")?;
-            // End the client handling
-            return Ok(());
+        tokio::select! {
+            request = requests.recv() => {
+                // Receive a request from the client
+                let request = request
+                    .ok_or(LibError::ConnectionClosed)
+                    .and_then(|request| request)
+                    .context("Request receiving failed")?;
+                trace!("Received request from client {}", peer);
+                // Create the responses based on the request
+                let responses = session
+                    .handle(request)
+                    .await
+                    .context("Failed to create response")?;
+                for response in responses {
+                    trace!("Sending response to {peer}");
+                    // Send the response to the client
+                    response
+                        .send(&mut writer, wire)
+                        .await
+                        .context("Response sending failed")?;
+                    // Shutdown the connection if Quit message
+                    if let MessageType::Quit = response.message {
+                        info!("Shutting down connection with {peer}");
+                        writer.shutdown().await.context("Failed to terminate connection")?;
+                        // End the client handling
+                        return Ok(());
+                    }
+                }
+            }
+            message = session.recv() => {
+                // Relay messages published to the room by other clients
+                trace!("Relaying room message to {peer}");
+                message.send(&mut writer, wire).await.context("Relaying failed")?;
+            }
+            _ = stopped(&mut shutdown) => {
+                // Tell the client to quit, like when it asks for it
+                info!("Server shutting down, closing connection with {peer}");
+                Envelope::new(SERVER_SENDER, MessageType::Quit)
+                    .send(&mut writer, wire)
+                    .await
+                    .context("Quit sending failed")?;
+                writer.shutdown().await.context("Failed to terminate connection")?;
+                return Ok(());
+            }
         }
     }
 }
 
-/// Receives a request from the client.
-async fn receive_request(stream: &mut TcpStream) -> Result<String> {
-    let mut buffer = [0; 100];
-    let n = match stream.read(&mut buffer).await {
-        Ok(0) => bail!("Connection closed"),
-        Ok(n) => n,
-        Err(e) => bail!(e),
+/// Performs the Hello/Welcome exchange, returns the Hello, the agreed settings and the admission
+/// if the client was accepted.
+async fn accept_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    hub: &Hub,
+) -> Result<Option<(Hello, Welcome, Admission)>> {
+    let mut admission = None;
+    let (hello, reply) = match timeout(HANDSHAKE_TIMEOUT, Hello::receive(stream)).await {
+        Ok(Ok(hello)) => {
+            let negotiated = hello.negotiate(SERVER_FEATURES, ALL_CODECS);
+            let (reply, admitted) = admit(&hello, hub, negotiated).await;
+            admission = admitted;
+            (Some(hello), reply)
+        }
+        Ok(Err(LibError::ConnectionClosed)) => bail!(LibError::ConnectionClosed),
+        // Most likely a client from before the handshake was introduced
+        Ok(Err(e)) => (
+            None,
+            HandshakeReply::Rejected(format!("Expected a Hello handshake ({e})")),
+        ),
+        Err(_) => (
+            None,
+            HandshakeReply::Rejected(String::from("Handshake timed out")),
+        ),
     };
-    // Convert bytes to string
-    let request =
-        String::from_utf8(buffer[..n].to_vec()).context("[u8] to String conversion failed")?;
-    Ok(request)
+    reply.send(stream).await.context("Reply sending failed")?;
+    match (hello, reply, admission) {
+        (Some(hello), HandshakeReply::Welcome(welcome), Some(admission)) => {
+            Ok(Some((hello, welcome, admission)))
+        }
+        (_, HandshakeReply::Rejected(reason), _) => {
+            info!("Rejected client: {reason}");
+            Ok(None)
+        }
+        (_, HandshakeReply::Welcome(_), _) => unreachable!("Welcome is only built from a Hello"),
+    }
+}
+
+/// Identity of a client accepted by the handshake.
+pub(crate) struct Admission {
+    /// Grant of the bot token the client authenticated with.
+    pub bot: Option<BotGrant>,
+    /// Whether the client logged into an account with its password.
+    pub account: bool,
+    /// Previous session the client resumed.
+    pub resumed: Option<Resumption>,
+    /// Key of the session token handed out in the Welcome.
+    pub key: [u8; 32],
+    /// Protocol version agreed in the Welcome.
+    pub version: u16,
 }
 
-/// Creates a response based on the client's request.
-async fn create_response(input: &String) -> Result<MessageType> {
-    // Create a message based on the input command
-    let message = if input.starts_with(".quit") {
-        MessageType::Quit
-    } else if input.starts_with(".file ") {
-        let path = Path::new(input.trim_start_matches(".file ").trim());
-        MessageType::from_file(path).await
-    } else if input.starts_with(".image ") {
-        let path = Path::new(input.trim_start_matches(".image ").trim());
-        MessageType::from_image(path).await
-    } else {
-        MessageType::from_text(input)
+/// Checks the password, bot or session token of a negotiated Hello and hands out a new session
+/// token.
+///
+/// Returns the final reply, and the admission if the client was accepted.
+pub(crate) async fn admit(
+    hello: &Hello,
+    hub: &Hub,
+    reply: HandshakeReply,
+) -> (HandshakeReply, Option<Admission>) {
+    let HandshakeReply::Welcome(mut welcome) = reply else {
+        return (reply, None);
+    };
+    let name = &hello.client_name;
+    // A resumed session keeps the grant the bot authenticated with and the account it logged into
+    let admitted = match &hello.resume {
+        Some(token) => hub
+            .sessions()
+            .resume(name, token)
+            .map(|resumed| (resumed.bot.clone(), resumed.account, Some(resumed)))
+            .map_err(|e| e.to_string()),
+        None => {
+            let bot = hub.bots().authenticate(name, hello.token.as_deref());
+            // Argon2 is slow, the copied hash is checked without holding the lock
+            let credentials = hub.accounts().credentials(name);
+            let password = hello.password.clone();
+            match bot {
+                Ok(bot) => hashing(move || match credentials {
+                    Some(credentials) => credentials.verify(password.as_deref()).map(|()| true),
+                    None => Ok(false),
+                })
+                .await
+                .map(|account| (bot, account, None))
+                .map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+    };
+    let (bot, account, resumed) = match admitted {
+        Ok(admitted) => admitted,
+        Err(reason) => return (HandshakeReply::Rejected(reason), None),
+    };
+    let (token, key) = hub.sessions().issue(name, bot.clone(), account);
+    welcome.session_token = Some(token);
+    let admission = Admission {
+        bot,
+        account,
+        resumed,
+        key,
+        version: welcome.version,
     };
-    Ok(message)
+    (HandshakeReply::Welcome(welcome), Some(admission))
 }
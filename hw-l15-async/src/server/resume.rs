@@ -0,0 +1,192 @@
+use crate::common::crypto::{random_token, token_hash as hash};
+use crate::server::bots::BotGrant;
+use chrono::{DateTime, Utc};
+use log::info;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Prefix of the session tokens, telling them apart from the bot tokens.
+const TOKEN_PREFIX: &str = "session_";
+/// Time a disconnected client has to resume its session.
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Reasons a session can't be resumed.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ResumeError {
+    #[error("Unknown session token, connect without it")]
+    UnknownToken,
+    #[error("Session expired, connect without the token")]
+    Expired,
+    #[error("Session token was issued to {0}")]
+    WrongName(String),
+}
+
+/// State of a session kept for its token.
+#[derive(Debug, Clone)]
+struct Tracked {
+    name: String,
+    room: Option<String>,
+    bot: Option<BotGrant>,
+    /// Whether the session logged into an account.
+    account: bool,
+    /// When the connection closed, `None` while it's open.
+    left: Option<(Instant, DateTime<Utc>)>,
+}
+
+/// What a resumed session gets back.
+#[derive(Debug, Clone)]
+pub struct Resumption {
+    pub name: String,
+    /// Room the session was in, `None` if it closed before joining one.
+    pub room: Option<String>,
+    pub bot: Option<BotGrant>,
+    pub account: bool,
+    /// Room messages posted after this time were missed.
+    pub since: DateTime<Utc>,
+}
+
+/// Short-lived tokens letting the clients resume their sessions, only their hashes are kept.
+pub struct SessionTokens {
+    ttl: Duration,
+    sessions: HashMap<[u8; 32], Tracked>,
+}
+
+impl Default for SessionTokens {
+    fn default() -> Self {
+        SessionTokens::new(DEFAULT_SESSION_TTL)
+    }
+}
+
+impl SessionTokens {
+    /// Creates the tokens valid for the TTL after their connection closes.
+    pub fn new(ttl: Duration) -> Self {
+        SessionTokens {
+            ttl,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Issues a token for a new session, returns it with the key the session is tracked by.
+    pub fn issue(
+        &mut self,
+        name: &str,
+        bot: Option<BotGrant>,
+        account: bool,
+    ) -> (String, [u8; 32]) {
+        // Sessions nobody resumed in time are forgotten
+        let ttl = self.ttl;
+        self.sessions
+            .retain(|_, tracked| !matches!(tracked.left, Some((left, _)) if left.elapsed() > ttl));
+        let token = random_token(TOKEN_PREFIX);
+        let key = hash(&token);
+        let tracked = Tracked {
+            name: name.to_string(),
+            room: None,
+            bot,
+            account,
+            left: None,
+        };
+        self.sessions.insert(key, tracked);
+        (token, key)
+    }
+
+    /// Remembers the room the session moved to.
+    pub fn moved(&mut self, key: &[u8; 32], room: &str) {
+        if let Some(tracked) = self.sessions.get_mut(key) {
+            tracked.room = Some(room.to_string());
+        }
+    }
+
+    /// Starts the TTL of the session, its connection closed.
+    pub fn left(&mut self, key: &[u8; 32]) {
+        if let Some(tracked) = self.sessions.get_mut(key) {
+            tracked.left = Some((Instant::now(), Utc::now()));
+        }
+    }
+
+    /// Drops every token issued to the name, none of its sessions can be resumed.
+    pub fn revoke(&mut self, name: &str) {
+        self.sessions.retain(|_, tracked| tracked.name != name);
+    }
+
+    /// Takes over the session of the token, which can't be used again.
+    ///
+    /// A session whose connection the server still considers open is taken over as well, e.g. when
+    /// the client noticed the network failure first.
+    pub fn resume(&mut self, name: &str, token: &str) -> Result<Resumption, ResumeError> {
+        let key = hash(token);
+        let tracked = self.sessions.get(&key).ok_or(ResumeError::UnknownToken)?;
+        if tracked.name != name {
+            return Err(ResumeError::WrongName(tracked.name.clone()));
+        }
+        let tracked = self
+            .sessions
+            .remove(&key)
+            .ok_or(ResumeError::UnknownToken)?;
+        let since = match tracked.left {
+            Some((left, _)) if left.elapsed() > self.ttl => return Err(ResumeError::Expired),
+            Some((_, since)) => since,
+            None => Utc::now(),
+        };
+        info!("{name} resumed the session in room {:?}", tracked.room);
+        Ok(Resumption {
+            name: tracked.name,
+            room: tracked.room,
+            bot: tracked.bot,
+            account: tracked.account,
+            since,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_keeps_room_once() {
+        let mut tokens = SessionTokens::default();
+        let (token, key) = tokens.issue("alice", None, false);
+        tokens.moved(&key, "rust");
+        tokens.left(&key);
+
+        assert_eq!(
+            tokens.resume("bob", &token).unwrap_err(),
+            ResumeError::WrongName(String::from("alice"))
+        );
+        let resumed = tokens.resume("alice", &token).unwrap();
+        assert_eq!(resumed.room.as_deref(), Some("rust"));
+        assert!(resumed.since <= Utc::now());
+        // The token is used up
+        assert_eq!(
+            tokens.resume("alice", &token).unwrap_err(),
+            ResumeError::UnknownToken
+        );
+    }
+
+    #[test]
+    fn test_revoked_names_can_not_resume() {
+        let mut tokens = SessionTokens::default();
+        let (alice, _) = tokens.issue("alice", None, false);
+        let (bob, _) = tokens.issue("bob", None, false);
+        tokens.revoke("alice");
+        assert_eq!(
+            tokens.resume("alice", &alice).unwrap_err(),
+            ResumeError::UnknownToken
+        );
+        assert!(tokens.resume("bob", &bob).is_ok());
+    }
+
+    #[test]
+    fn test_expired_session_is_refused() {
+        let mut tokens = SessionTokens::new(Duration::ZERO);
+        let (token, key) = tokens.issue("alice", None, false);
+        tokens.left(&key);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(
+            tokens.resume("alice", &token).unwrap_err(),
+            ResumeError::Expired
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::common::codec::Codec;
+use crate::common::envelope::{Envelope, SERVER_SENDER};
+use crate::common::handshake::{HandshakeReply, Hello};
+use crate::common::{LibError, MessageType};
+use crate::server::builder::stopped;
+use crate::server::hub::{Hub, Session};
+use crate::server::{admit, Admission, HANDSHAKE_TIMEOUT};
+use anyhow::{bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use log::{error, info, trace};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, WebSocketStream};
+
+/// Main loop to accept and handle incoming WebSocket connections.
+pub(crate) async fn ws_loop(
+    listener: TcpListener,
+    hub: Arc<Hub>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut clients = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = stopped(&mut shutdown) => break,
+        };
+        match accepted {
+            Ok((stream, peer_addr)) => {
+                info!("Accepted WebSocket connection from {:?}", peer_addr);
+
+                // Spawn a new task to handle each browser session
+                let (hub, shutdown) = (hub.clone(), shutdown.clone());
+                clients.spawn(async move {
+                    match handle_ws_client(stream, hub, shutdown).await {
+                        Ok(_) => info!("WebSocket client {:?} handled successfully", peer_addr),
+                        Err(e) => error!("Error handling WebSocket client {:?}: {}", peer_addr, e),
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Failed to accept WebSocket connection: {}", e);
+            }
+        }
+    }
+    // Let the connected clients quit gracefully
+    while clients.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Handles communication with a single WebSocket client exchanging JSON-encoded messages.
+async fn handle_ws_client(
+    stream: TcpStream,
+    hub: Arc<Hub>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut ws = accept_async(stream)
+        .await
+        .context("WebSocket handshake failed")?;
+    // The first frame has to be the JSON-encoded Hello
+    let Some((hello, admission)) = accept_ws_handshake(&mut ws, &hub)
+        .await
+        .context("Handshake failed")?
+    else {
+        ws.close(None)
+            .await
+            .context("Failed to terminate connection")?;
+        return Ok(());
+    };
+    info!(
+        "WebSocket client {peer} introduced itself as {}",
+        hello.client_name
+    );
+    let mut session = Session::admit(hub, hello.client_name, admission);
+    for envelope in session.retransmit() {
+        send_frame(&mut ws, &envelope).await?;
+    }
+    loop {
+        tokio::select! {
+            frame = ws.next() => {
+                let message = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | None => {
+                        info!("WebSocket client {peer} disconnected");
+                        return Ok(());
+                    }
+                    // Pings are answered by tungstenite itself
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e).context("Frame receiving failed"),
+                };
+                trace!("Received frame '{}' from WebSocket client {}", message, peer);
+                // Decode the request, reporting malformed frames back to the client
+                let request = match Envelope::from_json(&message).and_then(Envelope::decompress) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        let error = Envelope::new(SERVER_SENDER, MessageType::Text(e.to_string()));
+                        send_frame(&mut ws, &error).await?;
+                        continue;
+                    }
+                };
+                for response in session.handle(request).await? {
+                    send_frame(&mut ws, &response).await?;
+                    // Close the socket if Quit message
+                    if let MessageType::Quit = response.message {
+                        info!("Shutting down WebSocket connection with {peer}");
+                        ws.close(None).await.context("Failed to terminate connection")?;
+                        return Ok(());
+                    }
+                }
+            }
+            message = session.recv() => {
+                send_frame(&mut ws, &message).await?;
+            }
+            _ = stopped(&mut shutdown) => {
+                info!("Server shutting down, closing WebSocket connection with {peer}");
+                send_frame(&mut ws, &Envelope::new(SERVER_SENDER, MessageType::Quit)).await?;
+                ws.close(None).await.context("Failed to terminate connection")?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Performs the Hello/Welcome exchange, returns the Hello and the admission if the client was
+/// accepted.
+async fn accept_ws_handshake(
+    ws: &mut WebSocketStream<TcpStream>,
+    hub: &Hub,
+) -> Result<Option<(Hello, Admission)>> {
+    let mut admission = None;
+    let (hello, reply) = match timeout(HANDSHAKE_TIMEOUT, ws.next()).await {
+        Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str::<Hello>(&text) {
+            Ok(hello) => {
+                // Compression is pointless on top of JSON, payloads are plain arrays there
+                let negotiated = hello.negotiate(&[], &[Codec::Json]);
+                let (reply, admitted) = admit(&hello, hub, negotiated).await;
+                admission = admitted;
+                (Some(hello), reply)
+            }
+            Err(e) => (
+                None,
+                HandshakeReply::Rejected(format!("Expected a Hello handshake ({e})")),
+            ),
+        },
+        Ok(Some(Ok(_))) => (
+            None,
+            HandshakeReply::Rejected(String::from("Expected a Hello handshake")),
+        ),
+        Ok(Some(Err(e))) => return Err(e).context("Frame receiving failed"),
+        Ok(None) => bail!(LibError::ConnectionClosed),
+        Err(_) => (
+            None,
+            HandshakeReply::Rejected(String::from("Handshake timed out")),
+        ),
+    };
+    let frame = serde_json::to_string(&reply).context("Failed to encode reply")?;
+    ws.send(Message::Text(frame))
+        .await
+        .context("Reply sending failed")?;
+    match reply {
+        HandshakeReply::Welcome(_) => Ok(hello.zip(admission)),
+        HandshakeReply::Rejected(reason) => {
+            info!("Rejected WebSocket client: {reason}");
+            Ok(None)
+        }
+    }
+}
+
+/// Sends a message to the WebSocket client as a JSON text frame.
+async fn send_frame(ws: &mut WebSocketStream<TcpStream>, envelope: &Envelope) -> Result<()> {
+    let frame = envelope.to_json().context("Failed to encode message")?;
+    ws.send(Message::Text(frame))
+        .await
+        .context("Frame sending failed")
+}
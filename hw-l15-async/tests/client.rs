@@ -0,0 +1,267 @@
+use futures_util::StreamExt;
+use networking::client::chat::{ChatClient, Events};
+use networking::client::{create_client, hello, introduce};
+use networking::common::codec::{Codec, WireFormat};
+use networking::common::envelope::{AckState, Envelope, SERVER_SENDER};
+use networking::common::presence::Status;
+use networking::common::{Endpoint, MessageType, RefusalKind};
+use networking::server::Server;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use tokio::io::BufReader;
+
+/// Connects a client introducing itself with the name.
+async fn connect(addr: SocketAddr, name: &str) -> (ChatClient, Events) {
+    let IpAddr::V4(ip) = addr.ip() else {
+        panic!("Server is bound to IPv4");
+    };
+    ChatClient::connect(ip, addr.port(), name, Codec::Bincode)
+        .await
+        .unwrap()
+}
+
+/// Returns the next event that isn't presence, typing or a receipt.
+async fn next_content(events: &mut Events) -> Envelope {
+    loop {
+        let envelope = events.next().await.unwrap().unwrap();
+        if envelope.needs_ack() || matches!(envelope.message, MessageType::Quit) {
+            return envelope;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_clients_chat_and_quit() {
+    let server = Server::builder().port(0).start().await.unwrap();
+    let (alice, mut alice_events) = connect(server.local_addr(), "alice").await;
+    let (bob, mut bob_events) = connect(server.local_addr(), "bob").await;
+
+    let request = alice.send_text("hi bob").await.unwrap();
+    // The server gives the message its own ID and tells the sender
+    let id = loop {
+        let envelope = alice_events.next().await.unwrap().unwrap();
+        if let MessageType::Accepted { request: sent, id } = envelope.message {
+            assert_eq!(sent, request);
+            break id;
+        }
+    };
+    assert_ne!(id, request);
+    let echo = next_content(&mut alice_events).await;
+    assert!(matches!(echo.message, MessageType::Text(text) if text == "hi bob"));
+    let relayed = next_content(&mut bob_events).await;
+    assert_eq!((relayed.id, relayed.sender.as_str()), (id, "alice"));
+    assert!(matches!(relayed.message, MessageType::Text(text) if text == "[alice] hi bob"));
+
+    bob.request_file(Path::new("Cargo.toml")).await.unwrap();
+    let file = next_content(&mut bob_events).await;
+    assert_eq!(file.sender, SERVER_SENDER);
+    assert!(matches!(file.message, MessageType::File { name, .. } if name == "Cargo.toml"));
+
+    // The events end with the Quit of the server
+    bob.quit().await.unwrap();
+    let quit = next_content(&mut bob_events).await;
+    assert!(matches!(quit.message, MessageType::Quit));
+    assert!(bob_events.next().await.is_none());
+
+    server.shutdown();
+    server.join().await.unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_shares_rooms_with_tcp() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("chat-{}.sock", std::process::id()));
+    let server = Server::builder()
+        .port(0)
+        .unix(&path)
+        .unix_mode(0o600)
+        .start()
+        .await
+        .unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let (alice, mut alice_events) = ChatClient::connect_unix(&path, "alice", Codec::Json)
+        .await
+        .unwrap();
+    let (_bob, mut bob_events) = connect(server.local_addr(), "bob").await;
+
+    alice.send_text("hi over unix").await.unwrap();
+    let relayed = next_content(&mut bob_events).await;
+    assert!(matches!(relayed.message, MessageType::Text(text) if text == "[alice] hi over unix"));
+    let echo = next_content(&mut alice_events).await;
+    assert!(matches!(echo.message, MessageType::Text(text) if text == "hi over unix"));
+
+    // The socket file goes away with the server
+    server.shutdown();
+    server.join().await.unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn test_bot_posts_with_token() {
+    let server = Server::builder().port(0).start().await.unwrap();
+    let (id, token) = server
+        .hub()
+        .bots()
+        .issue("ci", &[String::from("lobby")])
+        .unwrap();
+    let IpAddr::V4(ip) = server.local_addr().ip() else {
+        panic!("Server is bound to IPv4");
+    };
+    let endpoint = Endpoint::Tcp(ip, server.local_addr().port());
+    // Nobody can take the name of the bot without its token
+    assert!(ChatClient::connect_to(&endpoint, "ci", Codec::Json)
+        .await
+        .is_err());
+    let (bot, mut bot_events) = ChatClient::connect_bot(&endpoint, "ci", &token, Codec::Json)
+        .await
+        .unwrap();
+    let (_alice, mut alice_events) = connect(server.local_addr(), "alice").await;
+
+    bot.send_text("build passed").await.unwrap();
+    let relayed = next_content(&mut alice_events).await;
+    assert!(relayed.bot);
+    assert!(matches!(relayed.message, MessageType::Text(text) if text == "[ci] build passed"));
+    let echo = next_content(&mut bot_events).await;
+    assert!(!echo.bot);
+    bot.send_text(".join random").await.unwrap();
+    let denied = next_content(&mut bot_events).await;
+    assert!(matches!(
+        denied.message,
+        MessageType::Error {
+            kind: RefusalKind::NotPermitted,
+            ..
+        }
+    ));
+
+    // A revoked token ends the session with the next message
+    server.hub().bots().revoke(id).unwrap();
+    bot.send_text("still here?").await.unwrap();
+    let quit = next_content(&mut bot_events).await;
+    assert!(matches!(quit.message, MessageType::Quit));
+    assert!(
+        ChatClient::connect_bot(&endpoint, "ci", &token, Codec::Json)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_accounts_log_in_with_password() {
+    let server = Server::builder().port(0).start().await.unwrap();
+    let hub = server.hub();
+    hub.accounts().add("root", "root-secret", true).unwrap();
+    hub.accounts().add("alice", "alice-secret", false).unwrap();
+    let IpAddr::V4(ip) = server.local_addr().ip() else {
+        panic!("Server is bound to IPv4");
+    };
+    let endpoint = Endpoint::Tcp(ip, server.local_addr().port());
+    // Names with an account need its password
+    assert!(ChatClient::connect_to(&endpoint, "alice", Codec::Json)
+        .await
+        .is_err());
+    assert!(
+        ChatClient::login(&endpoint, "alice", "wrong-secret", Codec::Json)
+            .await
+            .is_err()
+    );
+    let (alice, mut alice_events) =
+        ChatClient::login(&endpoint, "alice", "alice-secret", Codec::Json)
+            .await
+            .unwrap();
+    alice
+        .send_text(".passwd alice-secret alice-secret-2")
+        .await
+        .unwrap();
+    let changed = next_content(&mut alice_events).await;
+    assert!(matches!(changed.message, MessageType::Text(text) if text == "Password changed"));
+    alice.send_text(".user disable root").await.unwrap();
+    let denied = next_content(&mut alice_events).await;
+    assert!(matches!(
+        denied.message,
+        MessageType::Error {
+            kind: RefusalKind::NotPermitted,
+            ..
+        }
+    ));
+
+    // Disabling the account ends its session with the next message
+    let (root, mut root_events) = ChatClient::login(&endpoint, "root", "root-secret", Codec::Json)
+        .await
+        .unwrap();
+    root.send_text(".user disable alice").await.unwrap();
+    next_content(&mut root_events).await;
+    alice.send_text("still here?").await.unwrap();
+    let quit = next_content(&mut alice_events).await;
+    assert!(matches!(quit.message, MessageType::Quit));
+    assert!(
+        ChatClient::login(&endpoint, "alice", "alice-secret-2", Codec::Json)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_resume_session_after_disconnect() {
+    let server = Server::builder().port(0).start().await.unwrap();
+    let IpAddr::V4(ip) = server.local_addr().ip() else {
+        panic!("Server is bound to IPv4");
+    };
+    let endpoint = Endpoint::Tcp(ip, server.local_addr().port());
+    let (bob, mut bob_events) = connect(server.local_addr(), "bob").await;
+    bob.send_text(".join rust").await.unwrap();
+    let joined = next_content(&mut bob_events).await;
+    assert!(matches!(joined.message, MessageType::Joined { room } if room == "rust"));
+
+    // Alice joins the room, then her connection breaks
+    let stream = create_client(ip, server.local_addr().port()).await.unwrap();
+    let mut stream = BufReader::new(stream);
+    let welcome = introduce(&mut stream, &hello("alice", Codec::Json))
+        .await
+        .unwrap();
+    let wire = WireFormat::from(&welcome);
+    Envelope::new("alice", MessageType::from_text(".join rust"))
+        .send(&mut stream, wire)
+        .await
+        .unwrap();
+    loop {
+        let envelope = Envelope::receive(&mut stream, wire).await.unwrap();
+        if envelope.needs_ack() {
+            let read = envelope.ack("alice", AckState::Read);
+            read.send(&mut stream, wire).await.unwrap();
+        }
+        if matches!(envelope.message, MessageType::Joined { .. }) {
+            break;
+        }
+    }
+    drop(stream);
+    loop {
+        let envelope = bob_events.next().await.unwrap().unwrap();
+        if let MessageType::Presence {
+            status: Status::Offline,
+            ..
+        } = envelope.message
+        {
+            break;
+        }
+    }
+    bob.send_text("did you see this?").await.unwrap();
+    next_content(&mut bob_events).await;
+
+    let token = welcome.session_token.unwrap();
+    let (alice, mut alice_events) = ChatClient::resume(&endpoint, "alice", &token, Codec::Json)
+        .await
+        .unwrap();
+    let missed = next_content(&mut alice_events).await;
+    assert!(matches!(missed.message, MessageType::Text(text) if text == "[bob] did you see this?"));
+    // Still in the same room, with a fresh token
+    alice.send_text("yes").await.unwrap();
+    let relayed = next_content(&mut bob_events).await;
+    assert!(matches!(relayed.message, MessageType::Text(text) if text == "[alice] yes"));
+    assert_ne!(alice.welcome().session_token, Some(token.clone()));
+    assert!(ChatClient::resume(&endpoint, "alice", &token, Codec::Json)
+        .await
+        .is_err());
+}
@@ -0,0 +1,140 @@
+use networking::client::{create_client, handshake};
+use networking::common::codec::{Codec, WireFormat};
+use networking::common::envelope::{AckState, Envelope};
+use networking::common::handshake::{HandshakeReply, Hello, MIN_PROTOCOL_VERSION};
+use networking::common::MessageType;
+use networking::server::{Server, ServerHandle};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+/// Starts a server on free ports.
+async fn start() -> ServerHandle {
+    Server::builder().port(0).start().await.unwrap()
+}
+
+/// Connects a client introducing itself with the name.
+async fn connect(addr: SocketAddr, name: &str) -> (BufReader<TcpStream>, WireFormat) {
+    let IpAddr::V4(ip) = addr.ip() else {
+        panic!("Server is bound to IPv4");
+    };
+    let mut stream = create_client(ip, addr.port()).await.unwrap();
+    let welcome = handshake(&mut stream, name, Codec::Json).await.unwrap();
+    (BufReader::new(stream), WireFormat::from(&welcome))
+}
+
+#[tokio::test]
+async fn test_ephemeral_ports() {
+    let server = start().await;
+    let (addr, ws_addr) = (server.local_addr(), server.ws_addr());
+    assert_ne!(addr.port(), 0);
+    assert_ne!(ws_addr.port(), 0);
+    assert_ne!(addr, ws_addr);
+
+    // A second server gets different ports
+    let other = start().await;
+    assert_ne!(other.local_addr(), addr);
+    server.shutdown();
+    other.shutdown();
+    server.join().await.unwrap();
+    other.join().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_quits_clients() {
+    let server = start().await;
+    let (mut stream, wire) = connect(server.local_addr(), "alice").await;
+    Envelope::new("alice", MessageType::from_text(".uppercase hi"))
+        .send(&mut stream, wire)
+        .await
+        .unwrap();
+    let response = Envelope::receive(&mut stream, wire).await.unwrap();
+    assert!(matches!(response.message, MessageType::Text(text) if text == "HI"));
+
+    server.shutdown();
+    // Messages relayed before the shutdown may still arrive first
+    loop {
+        let envelope = Envelope::receive(&mut stream, wire).await.unwrap();
+        if let MessageType::Quit = envelope.message {
+            break;
+        }
+    }
+    server.join().await.unwrap();
+    assert!(TcpStream::connect(stream.get_ref().peer_addr().unwrap())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_older_client_is_answered_in_its_version() {
+    let server = start().await;
+    let mut stream = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+    let mut hello = Hello::new("alice", &[], &[Codec::Json]);
+    hello.version = MIN_PROTOCOL_VERSION;
+    hello.send(&mut stream).await.unwrap();
+    let HandshakeReply::Welcome(welcome) = HandshakeReply::receive(&mut stream).await.unwrap()
+    else {
+        panic!("Expected a Welcome");
+    };
+    assert_eq!(welcome.version, MIN_PROTOCOL_VERSION);
+    let wire = WireFormat::from(&welcome);
+
+    // Version 1 knows neither Accepted nor Joined
+    Envelope::new("alice", MessageType::from_text("hi"))
+        .send(&mut stream, wire)
+        .await
+        .unwrap();
+    let echo = Envelope::receive(&mut stream, wire).await.unwrap();
+    assert!(matches!(echo.message, MessageType::Text(text) if text == "hi"));
+    Envelope::new("alice", MessageType::from_text(".join rust"))
+        .send(&mut stream, wire)
+        .await
+        .unwrap();
+    let joined = Envelope::receive(&mut stream, wire).await.unwrap();
+    assert!(matches!(joined.message, MessageType::Text(text) if text == "Joined room rust"));
+}
+
+#[tokio::test]
+async fn test_unacknowledged_room_message_is_replayed() {
+    let server = start().await;
+    let (mut bob, wire) = connect(server.local_addr(), "bob").await;
+    let (mut alice, _) = connect(server.local_addr(), "alice").await;
+    Envelope::new("alice", MessageType::from_text("hi"))
+        .send(&mut alice, wire)
+        .await
+        .unwrap();
+    let relayed = loop {
+        let envelope = Envelope::receive(&mut bob, wire).await.unwrap();
+        if let MessageType::Text(_) = envelope.message {
+            break envelope;
+        }
+    };
+
+    // Bob drops the connection without acknowledging, so his next one gets the message first
+    drop(bob);
+    let (mut bob, _) = connect(server.local_addr(), "bob").await;
+    let replayed = Envelope::receive(&mut bob, wire).await.unwrap();
+    assert_eq!(replayed.id, relayed.id);
+    assert!(matches!(&replayed.message, MessageType::Text(text) if text == "[alice] hi"));
+    replayed
+        .ack("bob", AckState::Delivered)
+        .send(&mut bob, wire)
+        .await
+        .unwrap();
+    Envelope::new("bob", MessageType::from_text(".uppercase ok"))
+        .send(&mut bob, wire)
+        .await
+        .unwrap();
+    let response = Envelope::receive(&mut bob, wire).await.unwrap();
+    assert!(matches!(response.message, MessageType::Text(text) if text == "OK"));
+
+    // Once acknowledged it's gone
+    drop(bob);
+    let (mut bob, _) = connect(server.local_addr(), "bob").await;
+    Envelope::new("bob", MessageType::from_text(".uppercase again"))
+        .send(&mut bob, wire)
+        .await
+        .unwrap();
+    let response = Envelope::receive(&mut bob, wire).await.unwrap();
+    assert!(matches!(response.message, MessageType::Text(text) if text == "AGAIN"));
+}